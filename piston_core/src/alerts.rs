@@ -0,0 +1,254 @@
+use crate::{
+    config::{AlertCondition, AlertRule, AlertsConfig},
+    models::{KillSwitch, Tick},
+    portfolio::Portfolio,
+    stats::PortfolioStatsPublished,
+};
+use actix::prelude::*;
+use log::{error, info, warn};
+use piston_ipc::{messages::IpcMessage, IpcWriter};
+use piston_shared::*;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+/// Evaluates alert rules against every portfolio's stats cycle. An alert is raised when a rule
+/// becomes breached, stays open (raised or acknowledged) while it remains breached, and is
+/// cleared once it no longer is
+pub struct AlertActor {
+    rules: Vec<AlertRule>,
+    portfolios: HashMap<String, Addr<Portfolio>>,
+    log_file: Option<File>,
+    next_alert_id: AlertId,
+    /// Open alerts keyed by rule name and portfolio code
+    open: HashMap<(String, String), Alert>,
+    last_tick: HashMap<SecurityId, Instant>,
+    /// Highest total PnL seen today per portfolio, with the day it was seen on
+    high_water: HashMap<String, (u64, f64)>,
+    rejected_trade_count: HashMap<String, u32>,
+
+    ipc_writer: IpcWriter,
+}
+
+impl AlertActor {
+    pub fn new(
+        config: AlertsConfig,
+        portfolios: HashMap<String, Addr<Portfolio>>,
+    ) -> std::io::Result<Self> {
+        let log_file = match &config.log_path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            rules: config.rules,
+            portfolios,
+            log_file,
+            next_alert_id: 0,
+            open: HashMap::default(),
+            last_tick: HashMap::default(),
+            high_water: HashMap::default(),
+            rejected_trade_count: HashMap::default(),
+
+            ipc_writer: IpcWriter::new(),
+        })
+    }
+
+    /// Describe how the rule is breached, or `None` if it is not
+    fn breach(&self, condition: &AlertCondition, stats: &PortfolioStats) -> Option<String> {
+        match condition {
+            AlertCondition::RealizedPnlBelow(threshold) => (stats.pnl < *threshold)
+                .then(|| format!("realized PnL {} is below {}", stats.pnl, threshold)),
+            AlertCondition::UnrealizedPnlBelow(threshold) => (stats.unrealized_pnl < *threshold)
+                .then(|| {
+                    format!(
+                        "unrealized PnL {} is below {}",
+                        stats.unrealized_pnl, threshold
+                    )
+                }),
+            AlertCondition::DrawdownAbove(threshold) => {
+                let (_, high) = self.high_water[&stats.code];
+                let drawdown = high - (stats.pnl + stats.unrealized_pnl);
+                (drawdown > *threshold).then(|| {
+                    format!(
+                        "drawdown {} from today's high of {} is above {}",
+                        drawdown, high, threshold
+                    )
+                })
+            }
+            AlertCondition::StalePrice(max_age) => {
+                let max_age = Duration::from_secs(*max_age);
+                let mut stale: Vec<_> = stats
+                    .positions
+                    .iter()
+                    .filter(|p| {
+                        self.last_tick
+                            .get(&p.security.id)
+                            .is_none_or(|at| at.elapsed() > max_age)
+                    })
+                    .map(|p| p.security.ticker.clone())
+                    .collect();
+                stale.sort();
+                stale.dedup();
+
+                (!stale.is_empty()).then(|| {
+                    format!(
+                        "no price for {} in the last {}s",
+                        stale.join(", "),
+                        max_age.as_secs()
+                    )
+                })
+            }
+            AlertCondition::MarginCall => {
+                (stats.margin.state == MarginState::MarginCall).then(|| {
+                    format!(
+                        "NAV {} is below the maintenance margin requirement of {}",
+                        stats.nav, stats.margin.maintenance_requirement
+                    )
+                })
+            }
+            AlertCondition::RejectedTrades => {
+                let previous = self.rejected_trade_count.get(&stats.code).copied();
+                let rejected = stats.rejected_trade_count - previous.unwrap_or(0);
+                (rejected > 0).then(|| format!("{} trades rejected", rejected))
+            }
+        }
+    }
+
+    fn update_high_water(&mut self, stats: &PortfolioStats) {
+        let today = now() / SECONDS_PER_DAY;
+        let total = stats.pnl + stats.unrealized_pnl;
+        let entry = self
+            .high_water
+            .entry(stats.code.clone())
+            .or_insert((today, total));
+
+        if entry.0 != today {
+            *entry = (today, total);
+        }
+        entry.1 = entry.1.max(total);
+    }
+
+    fn publish(&mut self, alert: &Alert) {
+        match alert.severity {
+            Severity::Info => info!("ALERT: {:?}", alert),
+            Severity::Warning | Severity::Critical => warn!("ALERT: {:?}", alert),
+        }
+
+        if let Some(file) = &mut self.log_file {
+            let written = serde_json::to_string(alert)
+                .map_err(std::io::Error::from)
+                .and_then(|line| writeln!(file, "{}", line));
+            if let Err(e) = written {
+                error!("Failed to write alert to log, {:?}", e);
+            }
+        }
+
+        self.ipc_writer.send(&IpcMessage::Alert(alert.clone()));
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the unix epoch")
+        .as_secs()
+}
+
+impl Actor for AlertActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("Started AlertActor with {} rules", self.rules.len());
+    }
+}
+
+impl Handler<Tick> for AlertActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Tick, _: &mut Self::Context) -> Self::Result {
+        self.last_tick.insert(msg.security_id, Instant::now());
+    }
+}
+
+impl Handler<PortfolioStatsPublished> for AlertActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PortfolioStatsPublished, _: &mut Self::Context) -> Self::Result {
+        let stats = msg.0;
+        self.update_high_water(&stats);
+
+        let mut changed = vec![];
+        for rule in &self.rules {
+            if !rule.portfolios.is_empty() && !rule.portfolios.contains(&stats.code) {
+                continue;
+            }
+
+            let key = (rule.name.clone(), stats.code.clone());
+            match (
+                self.breach(&rule.condition, &stats),
+                self.open.contains_key(&key),
+            ) {
+                (Some(message), false) => {
+                    let alert = Alert {
+                        id: self.next_alert_id,
+                        rule: rule.name.clone(),
+                        portfolio_code: stats.code.clone(),
+                        severity: rule.severity,
+                        message,
+                        state: AlertState::Raised,
+                        raised_at: now(),
+                    };
+                    self.next_alert_id += 1;
+                    self.open.insert(key, alert.clone());
+                    changed.push(alert);
+
+                    if let Some(action) = rule.action {
+                        warn!("{} breached {}, pulling kill switch", stats.code, rule.name);
+                        self.portfolios[&stats.code].do_send(KillSwitch(action));
+                    }
+                }
+                (None, true) => {
+                    let mut alert = self.open.remove(&key).expect("Alert is open");
+                    alert.state = AlertState::Cleared;
+                    changed.push(alert);
+                }
+                _ => {}
+            }
+        }
+
+        self.rejected_trade_count
+            .insert(stats.code.clone(), stats.rejected_trade_count);
+
+        for alert in changed {
+            self.publish(&alert);
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Alert, String>")]
+pub struct AcknowledgeAlert(pub AlertId);
+
+impl Handler<AcknowledgeAlert> for AlertActor {
+    type Result = Result<Alert, String>;
+
+    fn handle(&mut self, msg: AcknowledgeAlert, _: &mut Self::Context) -> Self::Result {
+        let alert = self
+            .open
+            .values_mut()
+            .find(|a| a.id == msg.0)
+            .ok_or_else(|| format!("No open alert with id {}", msg.0))?;
+        alert.state = AlertState::Acknowledged;
+
+        let alert = alert.clone();
+        self.publish(&alert);
+
+        Ok(alert)
+    }
+}
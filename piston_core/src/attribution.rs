@@ -0,0 +1,114 @@
+use piston_shared::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Tracks what a `Portfolio`'s PnL is made of between stats cycles
+#[derive(Debug, Clone, Default)]
+pub struct PnlAttributor {
+    /// Realized PnL per security, with the security kept around for its groups
+    realized: HashMap<SecurityId, (Security, f64)>,
+    /// Unrealized PnL of every open position as of the previous stats cycle
+    marks: HashMap<PositionId, f64>,
+    new_trades: f64,
+    existing_positions: f64,
+}
+
+impl PnlAttributor {
+    /// Record a position being closed, after it has been marked at its closing price
+    pub fn on_close(&mut self, position: &Position) {
+        self.on_reduce(position, 1f64);
+    }
+
+    /// Record `fraction` of a position being closed. `closed` is the closed part of the position,
+    /// marked at its closing price
+    pub fn on_reduce(&mut self, closed: &Position, fraction: f64) {
+        let realized = closed.unrealized_pnl;
+        self.realized
+            .entry(closed.security.id)
+            .or_insert_with(|| (closed.security.clone(), 0f64))
+            .1 += realized;
+
+        match self.marks.get_mut(&closed.id) {
+            Some(mark) => {
+                let closed_mark = *mark * fraction;
+                self.existing_positions += realized - closed_mark;
+                *mark -= closed_mark;
+                if fraction >= 1f64 {
+                    self.marks.remove(&closed.id);
+                }
+            }
+            None => self.new_trades += realized,
+        }
+    }
+
+    /// Produce the attribution for this stats cycle and start the next one
+    pub fn attribute<'a>(
+        &mut self,
+        positions: impl Iterator<Item = &'a Position>,
+    ) -> PnlAttribution {
+        let mut new_trades = self.new_trades;
+        let mut price_move = 0f64;
+        let mut marks = HashMap::new();
+        let mut by_security: BTreeMap<SecurityId, SecurityPnl> = self
+            .realized
+            .iter()
+            .map(|(id, (security, pnl))| (*id, security_pnl(security, *pnl)))
+            .collect();
+        let mut securities: HashMap<SecurityId, &Security> = self
+            .realized
+            .iter()
+            .map(|(id, (security, _))| (*id, security))
+            .collect();
+
+        for p in positions {
+            match self.marks.get(&p.id) {
+                Some(mark) => price_move += p.unrealized_pnl - mark,
+                None => new_trades += p.unrealized_pnl,
+            }
+            marks.insert(p.id, p.unrealized_pnl);
+
+            by_security
+                .entry(p.security.id)
+                .or_insert_with(|| security_pnl(&p.security, 0f64))
+                .unrealized_pnl += p.unrealized_pnl;
+            securities.entry(p.security.id).or_insert(&p.security);
+        }
+
+        let mut by_group: BTreeMap<(&String, &String), GroupPnl> = BTreeMap::new();
+        for (id, security) in &securities {
+            let pnl = &by_security[id];
+            for (group, name) in &security.groups {
+                let entry = by_group.entry((group, name)).or_insert_with(|| GroupPnl {
+                    group: group.clone(),
+                    name: name.clone(),
+                    pnl: 0f64,
+                    unrealized_pnl: 0f64,
+                });
+                entry.pnl += pnl.pnl;
+                entry.unrealized_pnl += pnl.unrealized_pnl;
+            }
+        }
+
+        let attribution = PnlAttribution {
+            by_group: by_group.into_values().collect(),
+            by_security: by_security.into_values().collect(),
+            new_trades,
+            existing_positions: self.existing_positions,
+            price_move,
+        };
+
+        self.marks = marks;
+        self.new_trades = 0f64;
+        self.existing_positions = 0f64;
+
+        attribution
+    }
+}
+
+fn security_pnl(security: &Security, pnl: f64) -> SecurityPnl {
+    SecurityPnl {
+        security_id: security.id,
+        ticker: security.ticker.clone(),
+        pnl,
+        unrealized_pnl: 0f64,
+    }
+}
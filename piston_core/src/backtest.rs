@@ -0,0 +1,397 @@
+use crate::{
+    models::{Tick, SECURITY_UNIVERSE},
+    portfolio::TradeBooked,
+    stats::PortfolioStatsPublished,
+};
+use actix::prelude::*;
+use piston_shared::*;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+const SECONDS_PER_YEAR: f64 = 365.25 * 24f64 * 60f64 * 60f64;
+
+/// A tick from a historical dataset
+#[derive(Debug, Clone)]
+pub struct HistoricalTick {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub tick: Tick,
+}
+
+/// Load ticks from a CSV file of `timestamp,ticker,price` rows, ordered by timestamp. A header
+/// row is skipped
+pub fn load_ticks(path: impl AsRef<Path>) -> std::io::Result<Vec<HistoricalTick>> {
+    parse_ticks(&fs::read_to_string(path)?)
+}
+
+fn parse_ticks(contents: &str) -> std::io::Result<Vec<HistoricalTick>> {
+    let invalid = |line: usize, message: String| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("line {}: {}", line + 1, message),
+        )
+    };
+
+    let mut ticks = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let fields: Vec<_> = line.split(',').map(str::trim).collect();
+        if line.trim().is_empty() || (i == 0 && fields[0] == "timestamp") {
+            continue;
+        }
+        let [timestamp, ticker, price] = fields[..] else {
+            return Err(invalid(
+                i,
+                format!("expected 3 fields, got {}", fields.len()),
+            ));
+        };
+
+        let security = SECURITY_UNIVERSE
+            .iter()
+            .find(|s| s.ticker == ticker)
+            .ok_or_else(|| invalid(i, format!("unknown security {}", ticker)))?;
+        let price: f64 = price
+            .parse()
+            .map_err(|e| invalid(i, format!("bad price, {}", e)))?;
+        if !price.is_finite() || price <= 0f64 {
+            return Err(invalid(i, format!("invalid price {}", price)));
+        }
+        ticks.push(HistoricalTick {
+            timestamp: timestamp
+                .parse()
+                .map_err(|e| invalid(i, format!("bad timestamp, {}", e)))?,
+            tick: Tick {
+                security_id: security.id,
+                price,
+            },
+        });
+    }
+
+    ticks.sort_by_key(|t| t.timestamp);
+    Ok(ticks)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BacktestReport {
+    pub start: u64,
+    pub end: u64,
+    pub ticks: usize,
+    pub portfolios: Vec<PerformanceReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PerformanceReport {
+    pub code: String,
+    pub initial_capital: f64,
+    /// Realized and unrealized PnL at the end of the backtest, before fees
+    pub pnl: f64,
+    pub fees: f64,
+    pub nav: f64,
+    /// Change in NAV as a fraction of initial capital
+    pub total_return: f64,
+    /// Annualized from the returns between bars
+    pub sharpe: f64,
+    /// Largest fall in equity from a previous high, as a fraction of that high
+    pub max_drawdown: f64,
+    /// Traded notional as a multiple of initial capital
+    pub turnover: f64,
+    pub trades: u32,
+    /// Fraction of the trades closing or reducing a position that realized a profit
+    pub win_rate: f64,
+    pub by_security: Vec<SecurityPnl>,
+}
+
+/// Records every portfolio's trades and its PnL at the end of each bar of a backtest
+pub struct BacktestRecorder {
+    trades: HashMap<String, Vec<TradeBooked>>,
+    latest: HashMap<String, PortfolioStats>,
+    /// Timestamp of each bar, with every portfolio's NAV at the end of it
+    bars: Vec<(u64, HashMap<String, f64>)>,
+    tick_count: usize,
+}
+
+impl BacktestRecorder {
+    pub fn new() -> Self {
+        Self {
+            trades: HashMap::default(),
+            latest: HashMap::default(),
+            bars: vec![],
+            tick_count: 0,
+        }
+    }
+
+    fn performance(&self, stats: &PortfolioStats, initial_capital: f64) -> PerformanceReport {
+        let trades = self.trades.get(&stats.code).map_or(&[][..], |t| &t[..]);
+        let equity: Vec<_> = self
+            .bars
+            .iter()
+            .map(|(_, nav)| nav.get(&stats.code).copied().unwrap_or(initial_capital))
+            .collect();
+
+        let mut by_security: BTreeMap<SecurityId, SecurityPnl> = BTreeMap::new();
+        for t in trades {
+            by_security
+                .entry(t.security.id)
+                .or_insert_with(|| security_pnl(&t.security))
+                .pnl += t.realized_pnl;
+        }
+        for p in &stats.positions {
+            by_security
+                .entry(p.security.id)
+                .or_insert_with(|| security_pnl(&p.security))
+                .unrealized_pnl += p.unrealized_pnl;
+        }
+
+        let closing: Vec<_> = trades.iter().filter(|t| t.side == Side::Sell).collect();
+        let wins = closing.iter().filter(|t| t.realized_pnl > 0f64).count();
+        let pnl = stats.pnl + stats.unrealized_pnl;
+
+        PerformanceReport {
+            code: stats.code.clone(),
+            initial_capital,
+            pnl,
+            fees: stats.cash.iter().map(|c| c.fees).sum(),
+            nav: stats.nav,
+            total_return: stats.nav / initial_capital - 1f64,
+            sharpe: self.sharpe(&equity),
+            max_drawdown: max_drawdown(&equity),
+            turnover: trades
+                .iter()
+                .map(|t| f64::from(t.size) * t.price)
+                .sum::<f64>()
+                / initial_capital,
+            trades: trades.len() as u32,
+            win_rate: match closing.len() {
+                0 => 0f64,
+                n => wins as f64 / n as f64,
+            },
+            by_security: by_security.into_values().collect(),
+        }
+    }
+
+    /// Sharpe ratio of the returns between bars, annualized using the average time between bars
+    fn sharpe(&self, equity: &[f64]) -> f64 {
+        let returns: Vec<_> = equity.windows(2).map(|w| w[1] / w[0] - 1f64).collect();
+        let (Some((first, _)), Some((last, _))) = (self.bars.first(), self.bars.last()) else {
+            return 0f64;
+        };
+        if returns.len() < 2 || last == first {
+            return 0f64;
+        }
+
+        let n = returns.len() as f64;
+        let mean = returns.iter().sum::<f64>() / n;
+        let std_dev = (returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1f64)).sqrt();
+        if std_dev == 0f64 {
+            return 0f64;
+        }
+        let bars_per_year = SECONDS_PER_YEAR / ((last - first) as f64 / n);
+
+        mean / std_dev * bars_per_year.sqrt()
+    }
+}
+
+impl Default for BacktestRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn security_pnl(security: &Security) -> SecurityPnl {
+    SecurityPnl {
+        security_id: security.id,
+        ticker: security.ticker.clone(),
+        pnl: 0f64,
+        unrealized_pnl: 0f64,
+    }
+}
+
+fn max_drawdown(equity: &[f64]) -> f64 {
+    let mut high = f64::NEG_INFINITY;
+    let mut drawdown = 0f64;
+    for e in equity {
+        high = high.max(*e);
+        if high > 0f64 {
+            drawdown = drawdown.max((high - e) / high);
+        }
+    }
+
+    drawdown
+}
+
+impl Actor for BacktestRecorder {
+    type Context = Context<Self>;
+}
+
+impl Handler<TradeBooked> for BacktestRecorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: TradeBooked, _: &mut Self::Context) -> Self::Result {
+        self.trades
+            .entry(msg.portfolio_code.clone())
+            .or_default()
+            .push(msg);
+    }
+}
+
+impl Handler<PortfolioStatsPublished> for BacktestRecorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: PortfolioStatsPublished, _: &mut Self::Context) -> Self::Result {
+        self.latest.insert(msg.0.code.clone(), msg.0);
+    }
+}
+
+/// Close a bar, recording every portfolio's NAV as of its latest stats
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordBar {
+    pub timestamp: u64,
+    pub ticks: usize,
+}
+
+impl Handler<RecordBar> for BacktestRecorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordBar, _: &mut Self::Context) -> Self::Result {
+        let nav = self
+            .latest
+            .iter()
+            .map(|(code, stats)| (code.clone(), stats.nav))
+            .collect();
+        self.bars.push((msg.timestamp, nav));
+        self.tick_count += msg.ticks;
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "BacktestReport")]
+pub struct GetReport {
+    /// Starting capital of each portfolio
+    pub initial_capital: HashMap<String, f64>,
+}
+
+impl Handler<GetReport> for BacktestRecorder {
+    type Result = MessageResult<GetReport>;
+
+    fn handle(&mut self, msg: GetReport, _: &mut Self::Context) -> Self::Result {
+        let mut portfolios: Vec<_> = self
+            .latest
+            .values()
+            .map(|stats| {
+                let initial_capital = msg
+                    .initial_capital
+                    .get(&stats.code)
+                    .copied()
+                    .expect("No initial capital for portfolio");
+                self.performance(stats, initial_capital)
+            })
+            .collect();
+        portfolios.sort_by(|a, b| a.code.cmp(&b.code));
+
+        MessageResult(BacktestReport {
+            start: self.bars.first().map_or(0, |(t, _)| *t),
+            end: self.bars.last().map_or(0, |(t, _)| *t),
+            ticks: self.tick_count,
+            portfolios,
+        })
+    }
+}
+
+impl fmt::Display for BacktestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Backtest of {} ticks from {} to {}",
+            self.ticks, self.start, self.end
+        )?;
+        for p in &self.portfolios {
+            writeln!(f)?;
+            writeln!(f, "{}", p.code)?;
+            writeln!(f, "  PnL:           {:.2}", p.pnl)?;
+            writeln!(f, "  Fees:          {:.2}", p.fees)?;
+            writeln!(f, "  NAV:           {:.2}", p.nav)?;
+            writeln!(f, "  Total return:  {:.2}%", p.total_return * 100f64)?;
+            writeln!(f, "  Sharpe:        {:.2}", p.sharpe)?;
+            writeln!(f, "  Max drawdown:  {:.2}%", p.max_drawdown * 100f64)?;
+            writeln!(f, "  Turnover:      {:.2}x", p.turnover)?;
+            writeln!(f, "  Trades:        {}", p.trades)?;
+            writeln!(f, "  Win rate:      {:.2}%", p.win_rate * 100f64)?;
+            for s in &p.by_security {
+                writeln!(
+                    f,
+                    "  {:<8} realized {:.2}, unrealized {:.2}",
+                    s.ticker, s.pnl, s.unrealized_pnl
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(contents: &str) -> String {
+        let e = parse_ticks(contents).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        e.to_string()
+    }
+
+    #[test]
+    fn ticks_are_parsed_in_time_order() {
+        let ticks =
+            parse_ticks("timestamp,ticker,price\n20,MSFT,300.5\n\n10, AAPL ,150\n").unwrap();
+
+        assert_eq!(ticks.len(), 2);
+        assert_eq!(ticks[0].timestamp, 10);
+        assert_eq!(ticks[0].tick.security_id, 1);
+        assert_eq!(ticks[0].tick.price, 150f64);
+        assert_eq!(ticks[1].timestamp, 20);
+        assert_eq!(ticks[1].tick.security_id, 2);
+    }
+
+    #[test]
+    fn the_header_is_optional() {
+        assert_eq!(parse_ticks("10,AAPL,150").unwrap().len(), 1);
+        // Only the first row can be a header
+        assert!(error("10,AAPL,150\ntimestamp,ticker,price").starts_with("line 2:"));
+    }
+
+    #[test]
+    fn rows_need_three_fields() {
+        assert_eq!(
+            error("timestamp,ticker,price\n10,AAPL"),
+            "line 2: expected 3 fields, got 2"
+        );
+        assert_eq!(error("10,AAPL,150,1"), "line 1: expected 3 fields, got 4");
+    }
+
+    #[test]
+    fn unknown_tickers_are_rejected() {
+        assert_eq!(error("10,NOPE,150"), "line 1: unknown security NOPE");
+    }
+
+    #[test]
+    fn bad_timestamps_are_rejected() {
+        assert!(error("soon,AAPL,150").starts_with("line 1: bad timestamp"));
+    }
+
+    #[test]
+    fn prices_must_be_finite_and_positive() {
+        assert!(error("10,AAPL,cheap").starts_with("line 1: bad price"));
+        for price in ["NaN", "inf", "-inf", "0", "-1.5"] {
+            assert!(
+                error(&format!("10,AAPL,{}", price)).starts_with("line 1: invalid price"),
+                "{}",
+                price
+            );
+        }
+    }
+}
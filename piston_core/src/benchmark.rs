@@ -0,0 +1,193 @@
+use crate::{config::BenchmarkConfig, models::Tick, stats::PortfolioStatsPublished};
+use actix::prelude::*;
+use log::{debug, info};
+use piston_ipc::{messages::IpcMessage, IpcWriter};
+use piston_shared::*;
+use std::collections::{HashMap, VecDeque};
+
+/// Tracks each benchmarked portfolio's returns against its benchmark's, built from the tick
+/// stream, and publishes active return, tracking error and beta every stats cycle
+pub struct BenchmarkActor {
+    window: usize,
+    last_price: HashMap<SecurityId, f64>,
+    tracked: HashMap<String, Tracking>,
+
+    ipc_writer: IpcWriter,
+}
+
+struct Tracking {
+    name: String,
+    weights: Vec<(SecurityId, f64)>,
+    /// Total PnL, gross exposure and benchmark prices at the end of the previous stats cycle
+    previous: Option<(f64, f64, Vec<f64>)>,
+    portfolio_growth: f64,
+    benchmark_growth: f64,
+    /// Portfolio and benchmark return of each of the most recent stats cycles
+    returns: VecDeque<(f64, f64)>,
+}
+
+impl BenchmarkActor {
+    /// `benchmarks` is keyed by portfolio code
+    pub fn new(
+        benchmarks: HashMap<String, BenchmarkConfig>,
+        universe: &[Security],
+        window: usize,
+    ) -> Self {
+        let tracked = benchmarks
+            .into_iter()
+            .map(|(code, benchmark)| {
+                let weights = benchmark
+                    .weights()
+                    .into_iter()
+                    .map(|(ticker, weight)| {
+                        let security = universe
+                            .iter()
+                            .find(|s| s.ticker == ticker)
+                            .expect("Benchmark security is not in the universe");
+                        (security.id, weight)
+                    })
+                    .collect();
+                let tracking = Tracking {
+                    name: benchmark.name(),
+                    weights,
+                    previous: None,
+                    portfolio_growth: 1f64,
+                    benchmark_growth: 1f64,
+                    returns: VecDeque::default(),
+                };
+                (code, tracking)
+            })
+            .collect();
+
+        Self {
+            window,
+            last_price: HashMap::default(),
+            tracked,
+
+            ipc_writer: IpcWriter::new(),
+        }
+    }
+}
+
+impl Tracking {
+    fn update(&mut self, stats: &PortfolioStats, prices: Vec<f64>, window: usize) {
+        let pnl = stats.pnl + stats.unrealized_pnl;
+        let gross = stats
+            .positions
+            .iter()
+            .map(|p| p.market_value().abs())
+            .sum::<f64>();
+
+        let previous = self.previous.as_ref().filter(|(_, _, previous_prices)| {
+            // A return from a price that is not positive would be infinite or meaningless
+            previous_prices.iter().all(|p| p.is_finite() && *p > 0f64)
+        });
+        if let Some((previous_pnl, previous_gross, previous_prices)) = previous {
+            let total_weight = self.weights.iter().map(|(_, w)| w).sum::<f64>();
+            let benchmark_return = self
+                .weights
+                .iter()
+                .zip(prices.iter().zip(previous_prices))
+                .map(|((_, w), (price, previous))| w * (price / previous - 1f64))
+                .sum::<f64>()
+                / total_weight;
+            // A portfolio with nothing at risk has no return
+            let portfolio_return = match *previous_gross > 0f64 {
+                true => (pnl - previous_pnl) / previous_gross,
+                false => 0f64,
+            };
+
+            self.portfolio_growth *= 1f64 + portfolio_return;
+            self.benchmark_growth *= 1f64 + benchmark_return;
+            self.returns.push_back((portfolio_return, benchmark_return));
+            if self.returns.len() > window {
+                self.returns.pop_front();
+            }
+        }
+
+        self.previous = Some((pnl, gross, prices));
+    }
+
+    fn benchmark(&self, code: &str) -> PortfolioBenchmark {
+        let portfolio: Vec<_> = self.returns.iter().map(|r| r.0).collect();
+        let benchmark: Vec<_> = self.returns.iter().map(|r| r.1).collect();
+        let active: Vec<_> = self.returns.iter().map(|r| r.0 - r.1).collect();
+        let benchmark_variance = covariance(&benchmark, &benchmark);
+
+        PortfolioBenchmark {
+            code: code.to_string(),
+            benchmark: self.name.clone(),
+            observations: self.returns.len() as u32,
+            portfolio_return: self.portfolio_growth - 1f64,
+            benchmark_return: self.benchmark_growth - 1f64,
+            active_return: self.portfolio_growth - self.benchmark_growth,
+            tracking_error: covariance(&active, &active).sqrt(),
+            beta: match benchmark_variance > 0f64 {
+                true => covariance(&portfolio, &benchmark) / benchmark_variance,
+                false => 0f64,
+            },
+        }
+    }
+}
+
+/// Sample covariance, or zero with fewer than two observations
+fn covariance(xs: &[f64], ys: &[f64]) -> f64 {
+    let n = xs.len();
+    if n < 2 {
+        return 0f64;
+    }
+
+    let mean = |v: &[f64]| v.iter().sum::<f64>() / n as f64;
+    let (mean_x, mean_y) = (mean(xs), mean(ys));
+    xs.iter()
+        .zip(ys)
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>()
+        / (n - 1) as f64
+}
+
+impl Actor for BenchmarkActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!(
+            "Started BenchmarkActor tracking {} portfolios",
+            self.tracked.len()
+        );
+    }
+}
+
+impl Handler<Tick> for BenchmarkActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Tick, _: &mut Self::Context) -> Self::Result {
+        self.last_price.insert(msg.security_id, msg.price);
+    }
+}
+
+impl Handler<PortfolioStatsPublished> for BenchmarkActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PortfolioStatsPublished, _: &mut Self::Context) -> Self::Result {
+        let stats = msg.0;
+        let Some(tracking) = self.tracked.get_mut(&stats.code) else {
+            return;
+        };
+        // Wait until every security in the benchmark has ticked
+        let Some(prices) = tracking
+            .weights
+            .iter()
+            .map(|(id, _)| self.last_price.get(id).copied())
+            .collect::<Option<Vec<_>>>()
+        else {
+            return;
+        };
+
+        tracking.update(&stats, prices, self.window);
+        let benchmark = tracking.benchmark(&stats.code);
+        debug!("BENCHMARK: {:?}", benchmark);
+
+        self.ipc_writer
+            .send(&IpcMessage::PortfolioBenchmark(benchmark));
+    }
+}
@@ -0,0 +1,165 @@
+use actix::prelude::*;
+use dotenv::dotenv;
+use lazy_static::lazy_static;
+use log::info;
+use piston_core::{
+    backtest::{load_ticks, BacktestRecorder, GetReport, RecordBar},
+    config::Config,
+    matching::MatchingEngine,
+    models::{Flush, SECURITY_UNIVERSE},
+    orders::{OrderManager, SubscribeOrders},
+    portfolio::{Portfolio, SubscribeTrades},
+    security_cache::{SecurityCache, SecurityCacheActor, SubscribeTicks},
+    stats::{PortfolioStatsEvent, SubscribePortfolioStats},
+    strategy::{start_strategies, StrategyActor},
+};
+use std::{collections::HashMap, sync::RwLock};
+
+const USAGE: &str = "usage: backtest <ticks.csv> [--report <path>]";
+const DEFAULT_REPORT_PATH: &str = "backtest-report.json";
+
+lazy_static! {
+    static ref SECURITY_CACHE: RwLock<SecurityCache> =
+        RwLock::new(SecurityCache::new(SECURITY_UNIVERSE.to_vec()));
+}
+
+/// Replay a tick dataset through every portfolio that has strategies configured, as fast as the
+/// engine can process it, then print and write a performance report
+fn main() {
+    dotenv().ok();
+    env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let mut ticks_path = None;
+    let mut report_path = String::from(DEFAULT_REPORT_PATH);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--report" => match args.next() {
+                Some(path) => report_path = path,
+                None => exit_with(USAGE),
+            },
+            _ if ticks_path.is_none() => ticks_path = Some(arg),
+            _ => exit_with(USAGE),
+        }
+    }
+    let Some(ticks_path) = ticks_path else {
+        exit_with(USAGE)
+    };
+
+    let config = Config::load().expect("Failed to load config");
+    let ticks = load_ticks(ticks_path).expect("Failed to load ticks");
+    let traded: Vec<_> = config
+        .portfolios()
+        .into_iter()
+        .filter(|p| !p.strategies.is_empty())
+        .collect();
+    if traded.is_empty() {
+        exit_with("No portfolio has a strategy to backtest");
+    }
+
+    let system = System::new();
+    let report = system.block_on(async {
+        let security_cache_actor = SecurityCacheActor::new(&SECURITY_CACHE).start();
+        let recorder = BacktestRecorder::new().start();
+
+        let mut portfolios = HashMap::new();
+        for p in &traded {
+            let addr = Portfolio::new(p, config.margin.clone(), &SECURITY_CACHE).start();
+            addr.do_send(SubscribePortfolioStats(recorder.clone().recipient()));
+            addr.do_send(SubscribeTrades(recorder.clone().recipient()));
+            portfolios.insert(p.code.clone(), addr);
+        }
+
+        let order_manager = OrderManager::new(portfolios.clone(), &SECURITY_CACHE).start();
+        let matching_engine = config.matching.clone().map(|matching| {
+            let matching_engine = MatchingEngine::new(matching, order_manager.clone()).start();
+            order_manager.do_send(SubscribeOrders(matching_engine.clone().recipient()));
+            security_cache_actor.do_send(SubscribeTicks(matching_engine.clone().recipient()));
+            matching_engine
+        });
+        let mut strategies = vec![];
+        for p in &traded {
+            strategies.extend(start_strategies(
+                p,
+                &portfolios[&p.code],
+                &security_cache_actor,
+                &order_manager,
+            ));
+        }
+
+        info!(
+            "Backtesting {} over {} ticks",
+            portfolios.keys().cloned().collect::<Vec<_>>().join(", "),
+            ticks.len()
+        );
+        for bar in ticks.chunk_by(|a, b| a.timestamp == b.timestamp) {
+            for t in bar {
+                security_cache_actor
+                    .send(t.tick.clone())
+                    .await
+                    .expect("Failed to send tick");
+            }
+            settle(&strategies, matching_engine.as_ref()).await;
+
+            for addr in portfolios.values() {
+                addr.send(PortfolioStatsEvent)
+                    .await
+                    .expect("Failed to request stats");
+            }
+            // Strategies may trade on the stats
+            settle(&strategies, matching_engine.as_ref()).await;
+
+            recorder
+                .send(RecordBar {
+                    timestamp: bar[0].timestamp,
+                    ticks: bar.len(),
+                })
+                .await
+                .expect("Failed to record bar");
+        }
+
+        let initial_capital = traded
+            .iter()
+            .map(|p| (p.code.clone(), p.cash.total_capital()))
+            .collect();
+        recorder
+            .send(GetReport { initial_capital })
+            .await
+            .expect("Failed to build report")
+    });
+
+    print!("{}", report);
+    std::fs::write(
+        &report_path,
+        serde_json::to_string_pretty(&report).expect("Failed to serialize report"),
+    )
+    .expect("Failed to write report");
+    println!("\nReport written to {}", report_path);
+}
+
+/// Flush the chain a step's messages travel down, so everything they caused is booked before
+/// the next step. Strategies are flushed once their orders and trades have been accepted, then
+/// the matching engine once the fills of those orders and earlier ones have been booked. The
+/// portfolios and the recorder are only sent messages, and handle them in order
+async fn settle(
+    strategies: &[Addr<StrategyActor>],
+    matching_engine: Option<&Addr<MatchingEngine>>,
+) {
+    for strategy in strategies {
+        strategy
+            .send(Flush)
+            .await
+            .expect("Failed to flush strategy");
+    }
+    if let Some(matching_engine) = matching_engine {
+        matching_engine
+            .send(Flush)
+            .await
+            .expect("Failed to flush matching engine");
+    }
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
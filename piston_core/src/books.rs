@@ -0,0 +1,133 @@
+use crate::stats::{PortfolioStatsPublished, STATS_INTERVAL};
+use actix::prelude::*;
+use log::{debug, info};
+use piston_ipc::{messages::IpcMessage, IpcWriter};
+use piston_shared::*;
+use std::collections::HashMap;
+
+/// Rolls up the stats of its child books and portfolios into a single `BookStats`
+pub struct BookAggregator {
+    code: String,
+    level: BookLevel,
+    children: Vec<String>,
+    latest: HashMap<String, BookStats>,
+    subscribers: Vec<Recipient<BookStatsPublished>>,
+
+    ipc_writer: IpcWriter,
+}
+
+impl BookAggregator {
+    pub fn new(code: String, level: BookLevel, children: Vec<String>) -> Self {
+        Self {
+            code,
+            level,
+            children,
+            latest: HashMap::default(),
+            subscribers: vec![],
+
+            ipc_writer: IpcWriter::new(),
+        }
+    }
+
+    fn rollup(&self) -> BookStats {
+        self.latest.values().fold(
+            BookStats {
+                code: self.code.clone(),
+                level: self.level,
+                children: self.children.clone(),
+                position_count: 0,
+                trade_count: 0,
+                pnl: 0f64,
+                unrealized_pnl: 0f64,
+                gross_exposure: 0f64,
+                net_exposure: 0f64,
+                nav: 0f64,
+            },
+            |mut acc, child| {
+                acc.position_count += child.position_count;
+                acc.trade_count += child.trade_count;
+                acc.pnl += child.pnl;
+                acc.unrealized_pnl += child.unrealized_pnl;
+                acc.gross_exposure += child.gross_exposure;
+                acc.net_exposure += child.net_exposure;
+                acc.nav += child.nav;
+                acc
+            },
+        )
+    }
+}
+
+/// Collapse a portfolio's positions into the same shape as a book so it can be rolled up
+fn portfolio_rollup(stats: &PortfolioStats) -> BookStats {
+    BookStats {
+        code: stats.code.clone(),
+        level: BookLevel::Portfolio,
+        children: vec![],
+        position_count: stats.positions.len() as u32,
+        trade_count: stats.trade_count,
+        pnl: stats.pnl,
+        unrealized_pnl: stats.unrealized_pnl,
+        gross_exposure: stats.exposure.gross,
+        net_exposure: stats.exposure.net,
+        nav: stats.nav,
+    }
+}
+
+impl Actor for BookAggregator {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("Started BookAggregator for {:?} {}", self.level, self.code);
+        ctx.run_interval(STATS_INTERVAL, |act, _| {
+            if act.latest.is_empty() {
+                return;
+            }
+
+            let stats = act.rollup();
+            debug!(
+                "BOOK STATS: {} ({:?}), realized: {}, unrealized: {}",
+                stats.code, stats.level, stats.pnl, stats.unrealized_pnl
+            );
+
+            for sub in &act.subscribers {
+                sub.do_send(BookStatsPublished(stats.clone()));
+            }
+
+            act.ipc_writer.send(&IpcMessage::BookStats(stats));
+        });
+    }
+}
+
+/// Published by a `BookAggregator` to its parent after every stats cycle
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct BookStatsPublished(pub BookStats);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeBookStats(pub Recipient<BookStatsPublished>);
+
+impl Handler<PortfolioStatsPublished> for BookAggregator {
+    type Result = ();
+
+    fn handle(&mut self, msg: PortfolioStatsPublished, _: &mut Self::Context) -> Self::Result {
+        self.latest
+            .insert(msg.0.code.clone(), portfolio_rollup(&msg.0));
+    }
+}
+
+impl Handler<BookStatsPublished> for BookAggregator {
+    type Result = ();
+
+    fn handle(&mut self, msg: BookStatsPublished, _: &mut Self::Context) -> Self::Result {
+        self.latest.insert(msg.0.code.clone(), msg.0);
+    }
+}
+
+impl Handler<SubscribeBookStats> for BookAggregator {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeBookStats, _: &mut Self::Context) -> Self::Result {
+        self.subscribers.push(msg.0);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{config::CashConfig, models::CURRENCY};
+use piston_shared::*;
+use std::collections::BTreeMap;
+
+const BPS: f64 = 10_000f64;
+
+/// A portfolio's cash per currency. Buys are paid for and sells credited in the currency of the
+/// security traded, less the configured fees
+#[derive(Debug)]
+pub struct CashLedger {
+    config: CashConfig,
+    balances: BTreeMap<String, Balance>,
+}
+
+#[derive(Debug, Default)]
+struct Balance {
+    cash: f64,
+    fees: f64,
+    dividends: f64,
+}
+
+impl CashLedger {
+    pub fn new(config: CashConfig) -> Self {
+        let balances = config
+            .capital
+            .iter()
+            .map(|(currency, cash)| {
+                let balance = Balance {
+                    cash: *cash,
+                    ..Balance::default()
+                };
+                (currency.clone(), balance)
+            })
+            .collect();
+
+        Self { config, balances }
+    }
+
+    /// The currency a security trades in
+    pub fn currency(&self, security: &Security) -> String {
+        security
+            .groups
+            .get(CURRENCY)
+            .cloned()
+            .unwrap_or_else(|| self.config.base_currency.clone())
+    }
+
+    pub fn fee(&self, notional: f64) -> f64 {
+        (notional.abs() * self.config.fee_bps / BPS).max(self.config.min_fee)
+    }
+
+    fn available(&self, currency: &str) -> f64 {
+        self.balances.get(currency).map_or(0f64, |b| b.cash)
+    }
+
+    /// Check there is the cash to pay for a buy, fees included
+    pub fn check_buying_power(
+        &self,
+        security: &Security,
+        notional: f64,
+    ) -> Result<(), RejectReason> {
+        if !self.config.check_buying_power {
+            return Ok(());
+        }
+
+        let currency = self.currency(security);
+        let required = notional + self.fee(notional);
+        let available = self.available(&currency);
+        match required <= available {
+            true => Ok(()),
+            false => Err(RejectReason::InsufficientCash {
+                currency,
+                required,
+                available,
+            }),
+        }
+    }
+
+    pub fn buy(&mut self, security: &Security, notional: f64) {
+        let fee = self.fee(notional);
+        let balance = self.balances.entry(self.currency(security)).or_default();
+        balance.cash -= notional + fee;
+        balance.fees += fee;
+    }
+
+    pub fn sell(&mut self, security: &Security, notional: f64) {
+        let fee = self.fee(notional);
+        let balance = self.balances.entry(self.currency(security)).or_default();
+        balance.cash += notional - fee;
+        balance.fees += fee;
+    }
+
+    pub fn dividend(&mut self, security: &Security, amount: f64) {
+        let balance = self.balances.entry(self.currency(security)).or_default();
+        balance.cash += amount;
+        balance.dividends += amount;
+    }
+
+    /// Balances in every currency, valuing the positions held in each
+    pub fn balances<'a>(&self, positions: impl Iterator<Item = &'a Position>) -> Vec<CashBalance> {
+        let mut market_values: BTreeMap<String, f64> = BTreeMap::new();
+        for p in positions {
+            *market_values.entry(self.currency(&p.security)).or_default() += p.market_value();
+        }
+
+        let mut currencies: Vec<_> = self.balances.keys().cloned().collect();
+        currencies.extend(market_values.keys().cloned());
+        currencies.sort();
+        currencies.dedup();
+
+        currencies
+            .into_iter()
+            .map(|currency| {
+                let balance = self.balances.get(&currency);
+                let cash = balance.map_or(0f64, |b| b.cash);
+                let market_value = market_values.get(&currency).copied().unwrap_or(0f64);
+                CashBalance {
+                    cash,
+                    market_value,
+                    nav: cash + market_value,
+                    fees: balance.map_or(0f64, |b| b.fees),
+                    dividends: balance.map_or(0f64, |b| b.dividends),
+                    currency,
+                }
+            })
+            .collect()
+    }
+}
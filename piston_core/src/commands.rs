@@ -0,0 +1,530 @@
+use crate::{
+    alerts::{AcknowledgeAlert, AlertActor},
+    models::{
+        next_position_id, GetPnlHistory, GetPosition, GetSnapshot, KillSwitch, PayDividend, Resync,
+        Tick, Trade, TradeType,
+    },
+    orders::{CancelOrder, FillOrder, OrderManager, SubmitOrder},
+    portfolio::Portfolio,
+    scenario::RunScenarios,
+    security_cache::{SecurityCache, SecurityCacheActor},
+};
+use actix::prelude::*;
+use log::{debug, error, info};
+use piston_ipc::messages::{
+    self, DividendPaid, IpcError, IpcMessage, PnlHistory, Pong, PortfolioOverview,
+    PortfolioSnapshots, PortfolioSummary, Portfolios, PositionDetail, PriceTick, ScenarioResults,
+    Securities, SecurityPrice, TradeInjected, TradingStatus,
+};
+use piston_shared::{Order, Position, Scenario, Side};
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Sender},
+        RwLock,
+    },
+};
+
+/// Answers requests arriving on the message stream by querying the engine's actors
+pub struct CommandRouter {
+    portfolios: HashMap<String, Addr<Portfolio>>,
+    alerts: Addr<AlertActor>,
+    orders: Addr<OrderManager>,
+    security_cache_actor: Addr<SecurityCacheActor>,
+    security_cache: &'static RwLock<SecurityCache>,
+    scenarios: Vec<Scenario>,
+}
+
+impl CommandRouter {
+    pub fn new(
+        portfolios: HashMap<String, Addr<Portfolio>>,
+        alerts: Addr<AlertActor>,
+        orders: Addr<OrderManager>,
+        security_cache_actor: Addr<SecurityCacheActor>,
+        security_cache: &'static RwLock<SecurityCache>,
+        scenarios: Vec<Scenario>,
+    ) -> Self {
+        Self {
+            portfolios,
+            alerts,
+            orders,
+            security_cache_actor,
+            security_cache,
+            scenarios,
+        }
+    }
+
+    fn portfolio(&self, code: &str) -> Result<Addr<Portfolio>, String> {
+        self.portfolios
+            .get(code)
+            .cloned()
+            .ok_or_else(|| format!("Unknown portfolio {}", code))
+    }
+
+    fn portfolios_or_all(&self, codes: &[String]) -> Result<Vec<Addr<Portfolio>>, String> {
+        match codes.is_empty() {
+            true => Ok(self.portfolios.values().cloned().collect()),
+            false => codes.iter().map(|code| self.portfolio(code)).collect(),
+        }
+    }
+
+    fn get_portfolio(
+        &self,
+        request: messages::GetPortfolio,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        let portfolio = self.portfolio(&request.code)?;
+        actix::spawn(async move {
+            let response = match portfolio.send(GetSnapshot).await {
+                Ok(stats) => IpcMessage::PortfolioStats(stats),
+                Err(e) => error_message(format!("Failed to get portfolio, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+
+        Ok(())
+    }
+
+    /// Reply with every portfolio's headline numbers as of now, ordered by code
+    fn list_portfolios(&self, reply: Sender<IpcMessage>) {
+        let portfolios: Vec<_> = self.portfolios.values().cloned().collect();
+        actix::spawn(async move {
+            let mut overviews = vec![];
+            for portfolio in portfolios {
+                match portfolio.send(GetSnapshot).await {
+                    Ok(stats) => overviews.push(PortfolioOverview {
+                        code: stats.code.clone(),
+                        position_count: stats.positions.len(),
+                        summary: PortfolioSummary::of(&stats),
+                    }),
+                    Err(e) => {
+                        let _ =
+                            reply.send(error_message(format!("Failed to list portfolios, {}", e)));
+                        return;
+                    }
+                }
+            }
+
+            overviews.sort_by(|a, b| a.code.cmp(&b.code));
+            let _ = reply.send(IpcMessage::Portfolios(Portfolios {
+                portfolios: overviews,
+            }));
+        });
+    }
+
+    fn get_pnl_history(
+        &self,
+        request: messages::GetPnlHistory,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        let portfolio = self.portfolio(&request.code)?;
+        actix::spawn(async move {
+            let response = match portfolio.send(GetPnlHistory).await {
+                Ok(points) => IpcMessage::PnlHistory(PnlHistory {
+                    code: request.code,
+                    points,
+                }),
+                Err(e) => error_message(format!("Failed to get PnL history, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+
+        Ok(())
+    }
+
+    /// Reply with the latest snapshot of each portfolio, or of every portfolio if none are given
+    fn resync(&self, request: messages::Resync, reply: Sender<IpcMessage>) -> Result<(), String> {
+        let portfolios = self.portfolios_or_all(&request.portfolio_codes)?;
+        actix::spawn(async move {
+            let mut snapshots = vec![];
+            for portfolio in portfolios {
+                match portfolio.send(Resync).await {
+                    Ok(snapshot) => snapshots.push(snapshot),
+                    Err(e) => {
+                        let _ = reply.send(error_message(format!("Failed to resync, {}", e)));
+                        return;
+                    }
+                }
+            }
+
+            snapshots.sort_by(|a, b| a.stats.code.cmp(&b.stats.code));
+            let _ = reply.send(IpcMessage::PortfolioSnapshots(PortfolioSnapshots {
+                snapshots,
+            }));
+        });
+
+        Ok(())
+    }
+
+    fn get_position(
+        &self,
+        request: messages::GetPosition,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        let portfolio = self.portfolio(&request.portfolio_code)?;
+        actix::spawn(async move {
+            let response = match portfolio.send(GetPosition(request.position_id)).await {
+                Ok(Some(position)) => IpcMessage::PositionDetail(PositionDetail {
+                    portfolio_code: request.portfolio_code,
+                    position,
+                }),
+                Ok(None) => error_message(format!(
+                    "{} has no position {}",
+                    request.portfolio_code, request.position_id
+                )),
+                Err(e) => error_message(format!("Failed to get position, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+
+        Ok(())
+    }
+
+    fn list_securities(&self) -> IpcMessage {
+        let cache = self
+            .security_cache
+            .read()
+            .expect("could not read security cache");
+        let mut securities = cache.get_securities();
+        securities.sort_by_key(|s| s.id);
+        let securities = securities
+            .into_iter()
+            .map(|security| SecurityPrice {
+                price: cache.get_latest_price(security.id),
+                security,
+            })
+            .collect();
+
+        IpcMessage::Securities(Securities { securities })
+    }
+
+    fn get_security(&self, request: messages::GetSecurity) -> Result<IpcMessage, String> {
+        let cache = self
+            .security_cache
+            .read()
+            .expect("could not read security cache");
+        let security = cache
+            .get_security(request.security_id)
+            .ok_or_else(|| format!("Unknown security {}", request.security_id))?;
+
+        Ok(IpcMessage::Security(SecurityPrice {
+            price: cache.get_latest_price(security.id),
+            security,
+        }))
+    }
+
+    /// Book a trade directly into a portfolio, replying once it has been booked or rejected
+    fn inject_trade(
+        &self,
+        request: messages::InjectTrade,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        let portfolio = self.portfolio(&request.portfolio_code)?;
+        if request.size == 0 || !request.price.is_finite() || request.price <= 0f64 {
+            return Err(format!(
+                "Invalid trade of {} at {}",
+                request.size, request.price
+            ));
+        }
+        let security = self
+            .security_cache
+            .read()
+            .expect("could not read security cache")
+            .get_security(request.security_id)
+            .ok_or_else(|| format!("Unknown security {}", request.security_id))?;
+
+        let (trade_type, position_id) = match request.side {
+            Side::Buy => {
+                let id = next_position_id();
+                let position = Position {
+                    id,
+                    security,
+                    cost_basis: f64::from(request.size) * request.price,
+                    size: request.size,
+                    unrealized_pnl: 0f64,
+                };
+                (TradeType::Open(position), Some(id))
+            }
+            Side::Sell => {
+                let reduce = TradeType::Reduce {
+                    security_id: request.security_id,
+                    size: request.size,
+                    price: request.price,
+                };
+                (reduce, None)
+            }
+        };
+
+        actix::spawn(async move {
+            let trade = Trade {
+                portfolio_code: request.portfolio_code.clone(),
+                trade_type,
+            };
+            let response = match portfolio.send(trade).await {
+                Ok(Ok(())) => IpcMessage::TradeInjected(TradeInjected {
+                    portfolio_code: request.portfolio_code,
+                    security_id: request.security_id,
+                    side: request.side,
+                    size: request.size,
+                    price: request.price,
+                    position_id,
+                }),
+                Ok(Err(rejection)) => error_message(rejection.to_string()),
+                Err(e) => error_message(format!("Failed to inject trade, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+
+        Ok(())
+    }
+
+    /// Tick a security as the tick feed would, replying once every subscriber has been sent it
+    fn inject_tick(
+        &self,
+        request: messages::InjectTick,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        if !request.price.is_finite() || request.price <= 0f64 {
+            return Err(format!("Invalid price {}", request.price));
+        }
+        let security = self
+            .security_cache
+            .read()
+            .expect("could not read security cache")
+            .get_security(request.security_id)
+            .ok_or_else(|| format!("Unknown security {}", request.security_id))?;
+
+        let security_cache_actor = self.security_cache_actor.clone();
+        actix::spawn(async move {
+            let tick = Tick {
+                security_id: security.id,
+                price: request.price,
+            };
+            let response = match security_cache_actor.send(tick).await {
+                Ok(()) => IpcMessage::Tick(PriceTick {
+                    security_id: security.id,
+                    ticker: security.ticker,
+                    price: request.price,
+                }),
+                Err(e) => error_message(format!("Failed to inject tick, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+
+        Ok(())
+    }
+
+    fn acknowledge_alert(&self, request: messages::AcknowledgeAlert, reply: Sender<IpcMessage>) {
+        let alerts = self.alerts.clone();
+        actix::spawn(async move {
+            let response = match alerts.send(AcknowledgeAlert(request.id)).await {
+                Ok(Ok(alert)) => IpcMessage::Alert(alert),
+                Ok(Err(message)) => error_message(message),
+                Err(e) => error_message(format!("Failed to acknowledge alert, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+    }
+
+    /// Send an order request to the order manager, replying with the order's new state
+    fn order_request<M>(&self, msg: M, reply: Sender<IpcMessage>)
+    where
+        M: Message<Result = Result<Order, String>> + Send + 'static,
+        OrderManager: Handler<M>,
+    {
+        let orders = self.orders.clone();
+        actix::spawn(async move {
+            let response = match orders.send(msg).await {
+                Ok(Ok(order)) => IpcMessage::OrderUpdate(order),
+                Ok(Err(message)) => error_message(message),
+                Err(e) => error_message(format!("Failed to reach order manager, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+    }
+
+    fn kill_switch(
+        &self,
+        request: messages::KillSwitch,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        let portfolio = self.portfolio(&request.portfolio_code)?;
+
+        actix::spawn(async move {
+            let response = match portfolio.send(KillSwitch(request.action)).await {
+                Ok(Ok(state)) => IpcMessage::TradingStatus(TradingStatus {
+                    portfolio_code: request.portfolio_code,
+                    state,
+                }),
+                Ok(Err(rejection)) => error_message(rejection.to_string()),
+                Err(e) => error_message(format!("Failed to apply kill switch, {}", e)),
+            };
+            let _ = reply.send(response);
+        });
+
+        Ok(())
+    }
+
+    /// Pay the dividend to every portfolio, replying with the total paid
+    fn pay_dividend(
+        &self,
+        request: messages::PayDividend,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        if !request.per_share.is_finite() || request.per_share <= 0f64 {
+            return Err(format!("Invalid dividend per share {}", request.per_share));
+        }
+
+        let portfolios: Vec<_> = self.portfolios.values().cloned().collect();
+        actix::spawn(async move {
+            let mut total = 0f64;
+            for portfolio in portfolios {
+                let dividend = PayDividend {
+                    security_id: request.security_id,
+                    per_share: request.per_share,
+                };
+                match portfolio.send(dividend).await {
+                    Ok(amount) => total += amount,
+                    Err(e) => {
+                        let _ = reply.send(error_message(format!("Failed to pay dividend, {}", e)));
+                        return;
+                    }
+                }
+            }
+
+            let _ = reply.send(IpcMessage::DividendPaid(DividendPaid {
+                security_id: request.security_id,
+                per_share: request.per_share,
+                total,
+            }));
+        });
+
+        Ok(())
+    }
+
+    fn run_scenarios(
+        &self,
+        request: messages::RunScenarios,
+        reply: Sender<IpcMessage>,
+    ) -> Result<(), String> {
+        let scenarios = match request.scenarios.is_empty() {
+            true => self.scenarios.clone(),
+            false => request.scenarios,
+        };
+        let portfolios = self.portfolios_or_all(&request.portfolio_codes)?;
+
+        actix::spawn(async move {
+            let mut results = vec![];
+            for portfolio in portfolios {
+                match portfolio.send(RunScenarios(scenarios.clone())).await {
+                    Ok(r) => results.extend(r),
+                    Err(e) => {
+                        let _ =
+                            reply.send(error_message(format!("Failed to run scenarios, {}", e)));
+                        return;
+                    }
+                }
+            }
+
+            let _ = reply.send(IpcMessage::ScenarioResults(ScenarioResults { results }));
+        });
+
+        Ok(())
+    }
+}
+
+fn error_message(message: String) -> IpcMessage {
+    IpcMessage::Error(IpcError { message })
+}
+
+impl Actor for CommandRouter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("Started CommandRouter");
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Command {
+    pub request: IpcMessage,
+    pub reply: Sender<IpcMessage>,
+}
+
+impl Handler<Command> for CommandRouter {
+    type Result = ();
+
+    fn handle(&mut self, msg: Command, _: &mut Self::Context) -> Self::Result {
+        debug!("Got command, {:?}", msg.request);
+        let result = match msg.request {
+            IpcMessage::Ping(_) => {
+                let _ = msg.reply.send(IpcMessage::Pong(Pong));
+                Ok(())
+            }
+            IpcMessage::ListPortfolios(_) => {
+                self.list_portfolios(msg.reply.clone());
+                Ok(())
+            }
+            IpcMessage::GetPortfolio(request) => self.get_portfolio(request, msg.reply.clone()),
+            IpcMessage::GetPnlHistory(request) => self.get_pnl_history(request, msg.reply.clone()),
+            IpcMessage::ListSecurities(_) => {
+                let _ = msg.reply.send(self.list_securities());
+                Ok(())
+            }
+            IpcMessage::GetSecurity(request) => self.get_security(request).map(|response| {
+                let _ = msg.reply.send(response);
+            }),
+            IpcMessage::GetIpcMetrics(_) => {
+                let _ = msg
+                    .reply
+                    .send(IpcMessage::IpcMetrics(piston_ipc::metrics()));
+                Ok(())
+            }
+            IpcMessage::Resync(request) => self.resync(request, msg.reply.clone()),
+            IpcMessage::GetPosition(request) => self.get_position(request, msg.reply.clone()),
+            IpcMessage::InjectTrade(request) => self.inject_trade(request, msg.reply.clone()),
+            IpcMessage::InjectTick(request) => self.inject_tick(request, msg.reply.clone()),
+            IpcMessage::RunScenarios(request) => self.run_scenarios(request, msg.reply.clone()),
+            IpcMessage::KillSwitch(request) => self.kill_switch(request, msg.reply.clone()),
+            IpcMessage::AcknowledgeAlert(request) => {
+                self.acknowledge_alert(request, msg.reply.clone());
+                Ok(())
+            }
+            IpcMessage::PayDividend(request) => self.pay_dividend(request, msg.reply.clone()),
+            IpcMessage::SubmitOrder(order) => {
+                self.order_request(SubmitOrder(order), msg.reply.clone());
+                Ok(())
+            }
+            IpcMessage::CancelOrder(request) => {
+                self.order_request(CancelOrder(request.id), msg.reply.clone());
+                Ok(())
+            }
+            IpcMessage::ReportFill(fill) => {
+                self.order_request(FillOrder(fill), msg.reply.clone());
+                Ok(())
+            }
+            other => Err(format!("Unsupported request {:?}", other)),
+        };
+
+        if let Err(message) = result {
+            let _ = msg.reply.send(error_message(message));
+        }
+    }
+}
+
+/// Answers a request by handing it to the router and waiting for its reply. Called from the
+/// socket threads, never from within the actor system
+pub fn command_handler(
+    router: Addr<CommandRouter>,
+) -> impl Fn(IpcMessage) -> IpcMessage + Clone + Send + 'static {
+    move |request| {
+        let (tx, rx) = mpsc::channel();
+        router.do_send(Command { request, reply: tx });
+
+        rx.recv().unwrap_or_else(|e| {
+            error!("Command was dropped without a reply, {:?}", e);
+            error_message(String::from("Engine did not respond"))
+        })
+    }
+}
@@ -0,0 +1,653 @@
+use crate::models::{SECTOR, SECURITY_UNIVERSE};
+use piston_ipc::outbox::PublisherConfig;
+use piston_shared::*;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+/// Environment variable pointing at a JSON config file. When unset, the default config is used
+pub const CONFIG_PATH_VAR: &str = "PISTON_CONFIG";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub book: BookConfig,
+    #[serde(default)]
+    pub risk: RiskConfig,
+    /// Scenarios run when a scenario request does not provide its own
+    #[serde(default = "default_scenarios")]
+    pub scenarios: Vec<Scenario>,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+    /// Simulated exchange filling orders against the tick feed. When `null`, orders are only
+    /// filled by fills reported with `ReportFill` requests
+    #[serde(default = "default_matching")]
+    pub matching: Option<MatchingConfig>,
+    #[serde(default)]
+    pub margin: MarginConfig,
+    /// How the message stream queues messages for subscribers that read slowly
+    #[serde(default)]
+    pub ipc: PublisherConfig,
+    /// JSON API for scripts and notebooks. Only started when set, since it has no authentication
+    #[serde(default)]
+    pub http: Option<HttpConfig>,
+}
+
+/// A node in the book hierarchy (firm -> desk -> strategy), with portfolios as the leaves
+#[derive(Debug, Clone, Deserialize)]
+pub struct BookConfig {
+    pub code: String,
+    pub level: BookLevel,
+    #[serde(default)]
+    pub books: Vec<BookConfig>,
+    #[serde(default)]
+    pub portfolios: Vec<PortfolioConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PortfolioConfig {
+    pub code: String,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Strategies trading the portfolio. Portfolios without any are traded by the random
+    /// `TradeFeed`
+    #[serde(default)]
+    pub strategies: Vec<StrategyConfig>,
+    #[serde(default)]
+    pub benchmark: Option<BenchmarkConfig>,
+    #[serde(default)]
+    pub cash: CashConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CashConfig {
+    /// Currency of securities that do not have a `currency` group
+    pub base_currency: String,
+    /// Starting cash per currency
+    pub capital: BTreeMap<String, f64>,
+    /// Commission charged on every trade, in basis points of its notional
+    pub fee_bps: f64,
+    pub min_fee: f64,
+    /// Reject buys the portfolio does not have the cash to pay for, fees included
+    pub check_buying_power: bool,
+}
+
+impl CashConfig {
+    /// Starting cash summed across currencies at par
+    pub fn total_capital(&self) -> f64 {
+        self.capital.values().sum()
+    }
+}
+
+impl Default for CashConfig {
+    fn default() -> Self {
+        Self {
+            base_currency: String::from("USD"),
+            capital: BTreeMap::from([(String::from("USD"), 10_000_000f64)]),
+            fee_bps: 1f64,
+            min_fee: 1f64,
+            check_buying_power: true,
+        }
+    }
+}
+
+/// What a portfolio's performance is measured against
+#[derive(Debug, Clone, Deserialize)]
+pub enum BenchmarkConfig {
+    Security(String),
+    /// A basket whose return is the weighted average of its securities' returns
+    Basket(Vec<BasketWeight>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BasketWeight {
+    pub ticker: String,
+    pub weight: f64,
+}
+
+impl BenchmarkConfig {
+    pub fn weights(&self) -> Vec<(&str, f64)> {
+        match self {
+            BenchmarkConfig::Security(ticker) => vec![(ticker, 1f64)],
+            BenchmarkConfig::Basket(basket) => basket
+                .iter()
+                .map(|b| (b.ticker.as_str(), b.weight))
+                .collect(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.weights()
+            .iter()
+            .map(|(ticker, weight)| match self {
+                BenchmarkConfig::Security(_) => ticker.to_string(),
+                BenchmarkConfig::Basket(_) => format!("{} {}", weight, ticker),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StrategyConfig {
+    pub kind: StrategyKind,
+    /// Tickers the strategy trades, or every security when empty
+    #[serde(default)]
+    pub securities: Vec<String>,
+    #[serde(default)]
+    pub execution: Execution,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Execution {
+    /// Submit market orders to the order manager
+    #[default]
+    Orders,
+    /// Book trades straight into the portfolio at the tick price
+    Direct,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum StrategyKind {
+    /// Buy once the price has risen by `threshold` (a fraction) over the last `lookback` ticks,
+    /// and sell once it has fallen by as much
+    Momentum {
+        lookback: usize,
+        threshold: f64,
+        quantity: u32,
+    },
+    /// Buy once the price is `threshold` standard deviations below its mean over the last
+    /// `lookback` ticks, and sell once it is back above the mean
+    MeanReversion {
+        lookback: usize,
+        threshold: f64,
+        quantity: u32,
+    },
+}
+
+/// Pre-trade limits checked before a portfolio opens a position. Unset limits are not enforced
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub max_gross_exposure: Option<f64>,
+    pub max_net_exposure: Option<f64>,
+    /// Largest total size held in any one security
+    pub max_position_size: Option<u32>,
+    pub max_open_positions: Option<u32>,
+    /// Tickers the portfolio may not open positions in
+    pub restricted: Vec<String>,
+    /// Kill switch pulled when a trade is rejected for breaching a limit
+    pub breach_action: Option<KillSwitchAction>,
+}
+
+/// Parameters for historical-simulation VaR and Expected Shortfall
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RiskConfig {
+    /// Number of returns kept per security
+    pub window: usize,
+    pub confidence_levels: Vec<f64>,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        Self {
+            window: 250,
+            confidence_levels: vec![0.95, 0.99],
+        }
+    }
+}
+
+/// How the simulated exchange quotes each security around its latest tick
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MatchingConfig {
+    /// Distance between the bid and the ask, in basis points of the tick price
+    pub spread_bps: f64,
+    /// Quantity available on each side of the quote per tick. Orders larger than this are filled
+    /// over several ticks
+    pub quote_size: u32,
+    /// Extra slippage paid for taking the whole quote in one tick, in basis points. Smaller fills
+    /// pay proportionally less
+    pub impact_bps: f64,
+}
+
+impl Default for MatchingConfig {
+    fn default() -> Self {
+        Self {
+            spread_bps: 10f64,
+            quote_size: 500,
+            impact_bps: 25f64,
+        }
+    }
+}
+
+fn default_matching() -> Option<MatchingConfig> {
+    Some(MatchingConfig::default())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Address the HTTP server listens on. There is no authentication, so only listen on
+    /// interfaces on a trusted network
+    pub address: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            address: String::from("127.0.0.1:8080"),
+        }
+    }
+}
+
+/// Margin required against each position, by the `asset_class` group of its security
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MarginConfig {
+    /// Rates for securities whose asset class is not listed
+    pub default: MarginRates,
+    pub asset_classes: BTreeMap<String, MarginRates>,
+}
+
+/// Fractions of a position's market value that must be covered by the portfolio's NAV
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct MarginRates {
+    /// Required to open new positions
+    pub initial: f64,
+    /// Falling below this is a margin call
+    pub maintenance: f64,
+}
+
+impl MarginConfig {
+    pub fn rates(&self, asset_class: Option<&String>) -> MarginRates {
+        asset_class
+            .and_then(|c| self.asset_classes.get(c))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for MarginConfig {
+    fn default() -> Self {
+        Self {
+            default: MarginRates {
+                initial: 1f64,
+                maintenance: 1f64,
+            },
+            asset_classes: BTreeMap::from([(
+                String::from("Equity"),
+                MarginRates {
+                    initial: 0.5,
+                    maintenance: 0.25,
+                },
+            )]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub rules: Vec<AlertRule>,
+    /// File every alert is appended to as a line of JSON
+    pub log_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub severity: Severity,
+    pub condition: AlertCondition,
+    /// Portfolios the rule applies to, or every portfolio when empty
+    #[serde(default)]
+    pub portfolios: Vec<String>,
+    /// Kill switch pulled on the portfolio when the rule is breached
+    #[serde(default)]
+    pub action: Option<KillSwitchAction>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum AlertCondition {
+    RealizedPnlBelow(f64),
+    UnrealizedPnlBelow(f64),
+    /// Total PnL has fallen this far from its high for the day
+    DrawdownAbove(f64),
+    /// A held security has not ticked for this many seconds
+    StalePrice(u64),
+    /// The portfolio rejected a trade since the previous stats cycle
+    RejectedTrades,
+    /// NAV has fallen below the maintenance margin requirement
+    MarginCall,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        let rule = |name: &str, severity, condition| AlertRule {
+            name: name.to_string(),
+            severity,
+            condition,
+            portfolios: vec![],
+            action: None,
+        };
+
+        Self {
+            rules: vec![
+                AlertRule {
+                    action: Some(KillSwitchAction::Flatten),
+                    ..rule(
+                        "Realized loss",
+                        Severity::Critical,
+                        AlertCondition::RealizedPnlBelow(-1_000_000f64),
+                    )
+                },
+                rule(
+                    "Unrealized loss",
+                    Severity::Warning,
+                    AlertCondition::UnrealizedPnlBelow(-500_000f64),
+                ),
+                rule(
+                    "Drawdown",
+                    Severity::Warning,
+                    AlertCondition::DrawdownAbove(250_000f64),
+                ),
+                rule(
+                    "Stale price",
+                    Severity::Warning,
+                    AlertCondition::StalePrice(10),
+                ),
+                rule(
+                    "Margin call",
+                    Severity::Critical,
+                    AlertCondition::MarginCall,
+                ),
+                rule(
+                    "Rejected trades",
+                    Severity::Info,
+                    AlertCondition::RejectedTrades,
+                ),
+            ],
+            log_path: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> std::io::Result<Self> {
+        let config = match std::env::var(CONFIG_PATH_VAR) {
+            Ok(path) => Self::from_file(path)?,
+            Err(_) => Self::default(),
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let config: Self = serde_json::from_str(&contents)?;
+
+        Ok(config)
+    }
+
+    pub fn portfolios(&self) -> Vec<&PortfolioConfig> {
+        self.book.portfolios()
+    }
+
+    /// Reject configs that would otherwise only fail once the engine is running
+    fn validate(&self) -> std::io::Result<()> {
+        if let Some(c) = self
+            .risk
+            .confidence_levels
+            .iter()
+            .find(|c| **c <= 0f64 || **c >= 1f64)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Risk confidence level {} must be between 0 and 1", c),
+            ));
+        }
+
+        if let Some(matching) = &self.matching {
+            if matching.quote_size == 0 || matching.spread_bps < 0f64 || matching.impact_bps < 0f64
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Matching quote size must be positive and spread and impact must not be negative",
+                ));
+            }
+        }
+
+        for p in self.portfolios() {
+            for strategy in &p.strategies {
+                let (StrategyKind::Momentum {
+                    lookback,
+                    threshold,
+                    quantity,
+                }
+                | StrategyKind::MeanReversion {
+                    lookback,
+                    threshold,
+                    quantity,
+                }) = strategy.kind;
+                if lookback < 2 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Strategy lookback for {} must be at least 2", p.code),
+                    ));
+                }
+                if quantity == 0 || !threshold.is_finite() || threshold <= 0f64 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Strategy quantity and threshold for {} must be positive",
+                            p.code
+                        ),
+                    ));
+                }
+                if let Some(ticker) = strategy
+                    .securities
+                    .iter()
+                    .find(|t| !SECURITY_UNIVERSE.iter().any(|s| &s.ticker == *t))
+                {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown security {} in strategy for {}", ticker, p.code),
+                    ));
+                }
+            }
+        }
+
+        let margin = &self.margin;
+        if let Some(rates) = std::iter::once(&margin.default)
+            .chain(margin.asset_classes.values())
+            .find(|r| r.maintenance < 0f64 || r.maintenance > r.initial)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Margin rates {:?} must not be negative and maintenance must not exceed initial",
+                    rates
+                ),
+            ));
+        }
+
+        if self.ipc.queue_capacity == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "IPC queue capacity must be positive",
+            ));
+        }
+
+        for p in self.portfolios() {
+            let cash = &p.cash;
+            if cash.capital.values().any(|c| *c < 0f64)
+                || cash.fee_bps < 0f64
+                || cash.min_fee < 0f64
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Cash capital and fees for {} must not be negative", p.code),
+                ));
+            }
+        }
+
+        for p in self.portfolios() {
+            let Some(benchmark) = &p.benchmark else {
+                continue;
+            };
+            let weights = benchmark.weights();
+            if weights.is_empty() || weights.iter().any(|(_, w)| *w <= 0f64) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Benchmark for {} needs positive weights", p.code),
+                ));
+            }
+            if let Some((ticker, _)) = weights
+                .iter()
+                .find(|(t, _)| !SECURITY_UNIVERSE.iter().any(|s| s.ticker == *t))
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown security {} in benchmark for {}", ticker, p.code),
+                ));
+            }
+        }
+
+        // Codes are used to route trades and to roll stats up the hierarchy, so no two books or
+        // portfolios can share one
+        let mut seen = HashSet::new();
+        for b in self.book.books() {
+            if !seen.insert(&b.code) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Duplicate book code {} in config", b.code),
+                ));
+            }
+        }
+        for p in self.portfolios() {
+            if !seen.insert(&p.code) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Duplicate portfolio code {} in config", p.code),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl BookConfig {
+    /// This book and every book below it
+    pub fn books(&self) -> Vec<&BookConfig> {
+        std::iter::once(self)
+            .chain(self.books.iter().flat_map(|b| b.books()))
+            .collect()
+    }
+
+    pub fn portfolios(&self) -> Vec<&PortfolioConfig> {
+        self.books
+            .iter()
+            .flat_map(|b| b.portfolios())
+            .chain(self.portfolios.iter())
+            .collect()
+    }
+
+    pub fn children(&self) -> Vec<String> {
+        self.books
+            .iter()
+            .map(|b| b.code.clone())
+            .chain(self.portfolios.iter().map(|p| p.code.clone()))
+            .collect()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let portfolio = |code: &str| PortfolioConfig {
+            code: code.to_string(),
+            limits: LimitsConfig::default(),
+            strategies: vec![],
+            benchmark: None,
+            cash: CashConfig::default(),
+        };
+
+        Self {
+            book: BookConfig {
+                code: String::from("PISTON"),
+                level: BookLevel::Firm,
+                portfolios: vec![],
+                books: vec![BookConfig {
+                    code: String::from("EQUITIES"),
+                    level: BookLevel::Desk,
+                    portfolios: vec![],
+                    books: vec![
+                        BookConfig {
+                            code: String::from("MOMENTUM"),
+                            level: BookLevel::Strategy,
+                            books: vec![],
+                            portfolios: vec![portfolio("RMCF"), portfolio("ATAR")],
+                        },
+                        BookConfig {
+                            code: String::from("MEAN_REVERSION"),
+                            level: BookLevel::Strategy,
+                            books: vec![],
+                            portfolios: vec![PortfolioConfig {
+                                code: String::from("COLT"),
+                                limits: LimitsConfig {
+                                    max_open_positions: Some(10),
+                                    restricted: vec![String::from("TSLA")],
+                                    ..LimitsConfig::default()
+                                },
+                                strategies: vec![],
+                                benchmark: None,
+                                cash: CashConfig::default(),
+                            }],
+                        },
+                    ],
+                }],
+            },
+            risk: RiskConfig::default(),
+            scenarios: default_scenarios(),
+            alerts: AlertsConfig::default(),
+            matching: default_matching(),
+            margin: MarginConfig::default(),
+            ipc: PublisherConfig::default(),
+            http: None,
+        }
+    }
+}
+
+fn default_scenarios() -> Vec<Scenario> {
+    let relative = |target, fraction| Shock {
+        target,
+        shift: PriceShift::Relative(fraction),
+    };
+
+    vec![
+        Scenario {
+            name: String::from("Market -10%"),
+            shocks: vec![relative(ShockTarget::Universe, -0.1)],
+        },
+        Scenario {
+            name: String::from("Technology -20%"),
+            shocks: vec![relative(
+                ShockTarget::Group {
+                    group: String::from(SECTOR),
+                    name: String::from("Technology"),
+                },
+                -0.2,
+            )],
+        },
+        Scenario {
+            name: String::from("TSLA to zero"),
+            shocks: vec![relative(ShockTarget::Security(String::from("TSLA")), -1.0)],
+        },
+    ]
+}
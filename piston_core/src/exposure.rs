@@ -0,0 +1,76 @@
+use piston_shared::*;
+use std::collections::BTreeMap;
+
+/// Exposure of the positions at their current marks, in total, to the largest single name and
+/// per security group
+pub fn exposure<'a>(positions: impl Iterator<Item = &'a Position>) -> PortfolioExposure {
+    let mut by_security: BTreeMap<SecurityId, (&Security, f64)> = BTreeMap::new();
+    for p in positions {
+        by_security
+            .entry(p.security.id)
+            .or_insert((&p.security, 0f64))
+            .1 += p.market_value();
+    }
+
+    let mut total = Totals::default();
+    let mut by_group: BTreeMap<(&String, &String), Totals> = BTreeMap::new();
+    for (security, market_value) in by_security.values() {
+        total.add(*market_value);
+        for (group, name) in &security.groups {
+            by_group
+                .entry((group, name))
+                .or_default()
+                .add(*market_value);
+        }
+    }
+
+    let gross = total.long + total.short;
+    let concentration = |value: f64| match gross > 0f64 {
+        true => value / gross,
+        false => 0f64,
+    };
+    let largest = by_security
+        .values()
+        .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+        .map(|(security, market_value)| SecurityExposure {
+            security_id: security.id,
+            ticker: security.ticker.clone(),
+            market_value: *market_value,
+            concentration: concentration(market_value.abs()),
+        });
+
+    PortfolioExposure {
+        gross,
+        net: total.long - total.short,
+        long: total.long,
+        short: total.short,
+        largest,
+        by_group: by_group
+            .into_iter()
+            .map(|((group, name), t)| GroupExposure {
+                group: group.clone(),
+                name: name.clone(),
+                gross: t.long + t.short,
+                net: t.long - t.short,
+                long: t.long,
+                short: t.short,
+                concentration: concentration(t.long + t.short),
+            })
+            .collect(),
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    long: f64,
+    short: f64,
+}
+
+impl Totals {
+    fn add(&mut self, market_value: f64) {
+        match market_value >= 0f64 {
+            true => self.long += market_value,
+            false => self.short -= market_value,
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{
+    commands::{command_handler, CommandRouter},
+    config::HttpConfig,
+};
+use actix::Addr;
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json, Path, Query},
+    App, HttpResponse, HttpServer, Responder,
+};
+use log::{error, info};
+use piston_ipc::messages::{
+    GetPnlHistory, GetPortfolio, GetPosition, GetSecurity, InjectTick, InjectTrade, IpcError,
+    IpcMessage, ListPortfolios, ListSecurities,
+};
+use piston_shared::{PositionId, SecurityId, Side};
+use serde::Deserialize;
+
+/// Serve the JSON API on the configured address. Every request is answered by the router, as
+/// requests on the message stream are
+pub fn serve_http(config: &HttpConfig, router: Addr<CommandRouter>) -> std::io::Result<()> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(router.clone()))
+            .service(list_portfolios)
+            .service(get_portfolio)
+            .service(list_positions)
+            .service(get_position)
+            .service(get_pnl_history)
+            .service(submit_trade)
+            .service(list_securities)
+            .service(get_security)
+            .service(submit_tick)
+    })
+    // Stopping on ctrl-c is left to the engine, rather than stopping the API and carrying on
+    .disable_signals()
+    .bind(&config.address)?
+    .run();
+    info!("Serving the HTTP API on {}", config.address);
+    actix::spawn(server);
+
+    Ok(())
+}
+
+/// Hand a request to the router on a blocking thread, as the message stream would
+async fn ask(router: &Addr<CommandRouter>, request: IpcMessage) -> IpcMessage {
+    let handler = command_handler(router.clone());
+    web::block(move || handler(request))
+        .await
+        .unwrap_or_else(|e| {
+            IpcMessage::Error(IpcError {
+                message: format!("Engine did not respond, {}", e),
+            })
+        })
+}
+
+/// An error response, `status` when the engine answered with an error
+fn failed(response: IpcMessage, status: StatusCode) -> HttpResponse {
+    match response {
+        IpcMessage::Error(e) => HttpResponse::build(status).json(e),
+        other => {
+            error!("Unexpected response to HTTP request, {:?}", other);
+            HttpResponse::InternalServerError().json(IpcError {
+                message: String::from("Unexpected response from the engine"),
+            })
+        }
+    }
+}
+
+#[get("/portfolios")]
+async fn list_portfolios(router: Data<Addr<CommandRouter>>) -> impl Responder {
+    match ask(&router, IpcMessage::ListPortfolios(ListPortfolios)).await {
+        IpcMessage::Portfolios(p) => HttpResponse::Ok().json(p.portfolios),
+        other => failed(other, StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[get("/portfolios/{code}")]
+async fn get_portfolio(router: Data<Addr<CommandRouter>>, code: Path<String>) -> impl Responder {
+    let request = GetPortfolio {
+        code: code.into_inner(),
+    };
+    match ask(&router, IpcMessage::GetPortfolio(request)).await {
+        IpcMessage::PortfolioStats(stats) => HttpResponse::Ok().json(stats),
+        other => failed(other, StatusCode::NOT_FOUND),
+    }
+}
+
+#[get("/portfolios/{code}/positions")]
+async fn list_positions(router: Data<Addr<CommandRouter>>, code: Path<String>) -> impl Responder {
+    let request = GetPortfolio {
+        code: code.into_inner(),
+    };
+    match ask(&router, IpcMessage::GetPortfolio(request)).await {
+        IpcMessage::PortfolioStats(mut stats) => {
+            stats.positions.sort_by_key(|p| p.id);
+            HttpResponse::Ok().json(stats.positions)
+        }
+        other => failed(other, StatusCode::NOT_FOUND),
+    }
+}
+
+#[get("/portfolios/{code}/positions/{id}")]
+async fn get_position(
+    router: Data<Addr<CommandRouter>>,
+    path: Path<(String, PositionId)>,
+) -> impl Responder {
+    let (portfolio_code, position_id) = path.into_inner();
+    let request = GetPosition {
+        portfolio_code,
+        position_id,
+    };
+    match ask(&router, IpcMessage::GetPosition(request)).await {
+        IpcMessage::PositionDetail(detail) => HttpResponse::Ok().json(detail.position),
+        other => failed(other, StatusCode::NOT_FOUND),
+    }
+}
+
+#[derive(Deserialize)]
+struct PnlHistoryQuery {
+    /// Only points after this many seconds since the unix epoch
+    since: Option<u64>,
+}
+
+#[get("/portfolios/{code}/pnl")]
+async fn get_pnl_history(
+    router: Data<Addr<CommandRouter>>,
+    code: Path<String>,
+    query: Query<PnlHistoryQuery>,
+) -> impl Responder {
+    let request = GetPnlHistory {
+        code: code.into_inner(),
+    };
+    match ask(&router, IpcMessage::GetPnlHistory(request)).await {
+        IpcMessage::PnlHistory(mut history) => {
+            if let Some(since) = query.since {
+                history.points.retain(|p| p.at > since);
+            }
+            HttpResponse::Ok().json(history.points)
+        }
+        other => failed(other, StatusCode::NOT_FOUND),
+    }
+}
+
+#[derive(Deserialize)]
+struct TradeRequest {
+    security_id: SecurityId,
+    side: Side,
+    size: u32,
+    price: f64,
+}
+
+/// Book a trade straight into a portfolio. Trades the portfolio rejects, such as those over a
+/// limit, are answered with the reason
+#[post("/portfolios/{code}/trades")]
+async fn submit_trade(
+    router: Data<Addr<CommandRouter>>,
+    code: Path<String>,
+    trade: Json<TradeRequest>,
+) -> impl Responder {
+    let trade = trade.into_inner();
+    let request = InjectTrade {
+        portfolio_code: code.into_inner(),
+        security_id: trade.security_id,
+        side: trade.side,
+        size: trade.size,
+        price: trade.price,
+    };
+    match ask(&router, IpcMessage::InjectTrade(request)).await {
+        IpcMessage::TradeInjected(trade) => HttpResponse::Created().json(trade),
+        other => failed(other, StatusCode::UNPROCESSABLE_ENTITY),
+    }
+}
+
+#[get("/securities")]
+async fn list_securities(router: Data<Addr<CommandRouter>>) -> impl Responder {
+    match ask(&router, IpcMessage::ListSecurities(ListSecurities)).await {
+        IpcMessage::Securities(s) => HttpResponse::Ok().json(s.securities),
+        other => failed(other, StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[get("/securities/{id}")]
+async fn get_security(router: Data<Addr<CommandRouter>>, id: Path<SecurityId>) -> impl Responder {
+    let request = GetSecurity {
+        security_id: id.into_inner(),
+    };
+    match ask(&router, IpcMessage::GetSecurity(request)).await {
+        IpcMessage::Security(security) => HttpResponse::Ok().json(security),
+        other => failed(other, StatusCode::NOT_FOUND),
+    }
+}
+
+#[derive(Deserialize)]
+struct TickRequest {
+    price: f64,
+}
+
+/// Tick a security as the tick feed would
+#[post("/securities/{id}/ticks")]
+async fn submit_tick(
+    router: Data<Addr<CommandRouter>>,
+    id: Path<SecurityId>,
+    tick: Json<TickRequest>,
+) -> impl Responder {
+    let request = InjectTick {
+        security_id: id.into_inner(),
+        price: tick.price,
+    };
+    match ask(&router, IpcMessage::InjectTick(request)).await {
+        IpcMessage::Tick(tick) => HttpResponse::Created().json(tick),
+        other => failed(other, StatusCode::UNPROCESSABLE_ENTITY),
+    }
+}
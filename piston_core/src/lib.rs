@@ -0,0 +1,23 @@
+pub mod alerts;
+pub mod attribution;
+pub mod backtest;
+pub mod benchmark;
+pub mod books;
+pub mod cash;
+pub mod commands;
+pub mod config;
+pub mod exposure;
+pub mod http;
+pub mod limits;
+pub mod margin;
+pub mod matching;
+pub mod models;
+pub mod orders;
+pub mod portfolio;
+pub mod risk;
+pub mod scenario;
+pub mod security_cache;
+pub mod stats;
+pub mod strategy;
+pub mod tick_feed;
+pub mod trade_feed;
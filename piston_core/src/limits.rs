@@ -0,0 +1,63 @@
+use crate::config::LimitsConfig;
+use piston_shared::*;
+
+/// Check whether opening `new` would breach any of the portfolio's limits. Exposure is measured
+/// at the latest price where one is known, falling back to the position's last mark
+pub fn check_limits<'a>(
+    limits: &LimitsConfig,
+    positions: impl Iterator<Item = &'a Position> + Clone,
+    new: &'a Position,
+    latest_price: impl Fn(SecurityId) -> Option<f64>,
+) -> Result<(), RejectReason> {
+    let ticker = &new.security.ticker;
+    if limits.restricted.contains(ticker) {
+        return Err(RejectReason::RestrictedSecurity {
+            ticker: ticker.clone(),
+        });
+    }
+
+    if let Some(limit) = limits.max_position_size {
+        // Summed wide so sizes near the top of `u32` cannot wrap past the limit
+        let resulting = positions
+            .clone()
+            .filter(|p| p.security.id == new.security.id)
+            .map(|p| u64::from(p.size))
+            .sum::<u64>()
+            + u64::from(new.size);
+        if resulting > u64::from(limit) {
+            return Err(RejectReason::PositionSize {
+                ticker: ticker.clone(),
+                limit,
+                resulting,
+            });
+        }
+    }
+
+    if let Some(limit) = limits.max_open_positions {
+        if positions.clone().count() as u32 >= limit {
+            return Err(RejectReason::OpenPositions { limit });
+        }
+    }
+
+    let market_value = |p: &Position| match latest_price(p.security.id) {
+        Some(price) => f64::from(p.size) * price,
+        None => p.market_value(),
+    };
+    let values: Vec<_> = positions.chain(Some(new)).map(market_value).collect();
+
+    if let Some(limit) = limits.max_gross_exposure {
+        let resulting = values.iter().map(|v| v.abs()).sum::<f64>();
+        if resulting > limit {
+            return Err(RejectReason::GrossExposure { limit, resulting });
+        }
+    }
+
+    if let Some(limit) = limits.max_net_exposure {
+        let resulting = values.iter().sum::<f64>();
+        if resulting.abs() > limit {
+            return Err(RejectReason::NetExposure { limit, resulting });
+        }
+    }
+
+    Ok(())
+}
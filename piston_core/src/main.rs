@@ -1,22 +1,26 @@
 use actix::prelude::*;
 use dotenv::dotenv;
 use lazy_static::lazy_static;
-
-use security_cache::{SecurityCache, SecurityCacheActor};
-use stats::PortfolioStatsFeed;
+use piston_core::{
+    alerts::AlertActor,
+    benchmark::BenchmarkActor,
+    books::{BookAggregator, SubscribeBookStats},
+    commands::{command_handler, CommandRouter},
+    config::{BookConfig, Config, MarginConfig},
+    http::serve_http,
+    matching::MatchingEngine,
+    models::SECURITY_UNIVERSE,
+    orders::{OrderManager, SubscribeOrders},
+    portfolio::Portfolio,
+    risk::RiskActor,
+    security_cache::{SecurityCache, SecurityCacheActor, SubscribeTicks},
+    stats::{PortfolioStatsFeed, SubscribePortfolioStats},
+    strategy::start_strategies,
+    tick_feed::TickFeed,
+    trade_feed::TradeFeed,
+};
+use piston_ipc::IpcPublisher;
 use std::{collections::HashMap, sync::RwLock, time::Duration};
-use tick_feed::TickFeed;
-use trade_feed::TradeFeed;
-
-mod models;
-mod portfolio;
-mod security_cache;
-mod stats;
-mod tick_feed;
-mod trade_feed;
-
-use models::SECURITY_UNIVERSE;
-use portfolio::Portfolio;
 
 lazy_static! {
     static ref SECURITY_CACHE: RwLock<SecurityCache> =
@@ -29,29 +33,110 @@ fn main() {
     let system = System::new();
 
     let timescale = Duration::from_millis(1000);
+    let config = Config::load().expect("Failed to load config");
 
     system.block_on(async {
         let security_cache_actor = SecurityCacheActor::new(&SECURITY_CACHE).start();
 
-        let portfolios = get_portfolios(&SECURITY_CACHE);
-        let portfolio_addr_map: HashMap<_, _> = portfolios
+        let mut portfolio_addr_map = HashMap::new();
+        start_book(
+            &config.book,
+            &config.margin,
+            &SECURITY_CACHE,
+            &mut portfolio_addr_map,
+        );
+        let portfolio_addrs: Vec<_> = portfolio_addr_map.values().cloned().collect();
+
+        let risk_actor = RiskActor::new(config.risk.clone()).start();
+        let alert_actor = AlertActor::new(config.alerts.clone(), portfolio_addr_map.clone())
+            .expect("Failed to create alert actor")
+            .start();
+        let benchmarks = config
+            .portfolios()
             .into_iter()
-            .map(|p| (p.code.clone(), p.start()))
+            .filter_map(|p| Some((p.code.clone(), p.benchmark.clone()?)))
             .collect();
-        let portfolio_addrs: Vec<_> = portfolio_addr_map.values().cloned().collect();
+        let benchmark_actor =
+            BenchmarkActor::new(benchmarks, &SECURITY_UNIVERSE, config.risk.window).start();
+        security_cache_actor.do_send(SubscribeTicks(benchmark_actor.clone().recipient()));
+        security_cache_actor.do_send(SubscribeTicks(risk_actor.clone().recipient()));
+        security_cache_actor.do_send(SubscribeTicks(alert_actor.clone().recipient()));
+        for addr in &portfolio_addrs {
+            addr.do_send(SubscribePortfolioStats(risk_actor.clone().recipient()));
+            addr.do_send(SubscribePortfolioStats(benchmark_actor.clone().recipient()));
+            addr.do_send(SubscribePortfolioStats(alert_actor.clone().recipient()));
+        }
 
-        TickFeed::new(security_cache_actor, timescale).start();
-        TradeFeed::new(portfolio_addr_map, &SECURITY_CACHE, timescale).start();
+        let order_manager = OrderManager::new(portfolio_addr_map.clone(), &SECURITY_CACHE).start();
+        for addr in &portfolio_addrs {
+            addr.do_send(SubscribePortfolioStats(order_manager.clone().recipient()));
+        }
+        if let Some(matching) = config.matching.clone() {
+            let matching_engine = MatchingEngine::new(matching, order_manager.clone()).start();
+            order_manager.do_send(SubscribeOrders(matching_engine.clone().recipient()));
+            security_cache_actor.do_send(SubscribeTicks(matching_engine.recipient()));
+        }
+
+        // Portfolios with strategies are traded by them, the rest by the random trade feed
+        let mut randomly_traded = portfolio_addr_map.clone();
+        for p in config.portfolios() {
+            if !p.strategies.is_empty() {
+                start_strategies(
+                    p,
+                    &portfolio_addr_map[&p.code],
+                    &security_cache_actor,
+                    &order_manager,
+                );
+                randomly_traded.remove(&p.code);
+            }
+        }
+
+        let router = CommandRouter::new(
+            portfolio_addr_map.clone(),
+            alert_actor,
+            order_manager,
+            security_cache_actor.clone(),
+            &SECURITY_CACHE,
+            config.scenarios.clone(),
+        )
+        .start();
+        IpcPublisher::bind(config.ipc.clone())
+            .expect("Failed to bind ipc socket")
+            .serve(command_handler(router.clone()));
+        if let Some(http) = &config.http {
+            serve_http(http, router).expect("Failed to serve HTTP API");
+        }
+
+        TickFeed::new(security_cache_actor, &SECURITY_CACHE, timescale).start();
+        if !randomly_traded.is_empty() {
+            TradeFeed::new(randomly_traded, &SECURITY_CACHE, timescale).start();
+        }
         PortfolioStatsFeed::new(portfolio_addrs).start()
     });
 
     system.run().expect("Failed to run the system");
 }
 
-fn get_portfolios(security_cache: &'static RwLock<SecurityCache>) -> Vec<Portfolio> {
-    vec![
-        Portfolio::new(String::from("RMCF"), security_cache),
-        Portfolio::new(String::from("ATAR"), security_cache),
-        Portfolio::new(String::from("COLT"), security_cache),
-    ]
+/// Start the portfolios and aggregators for a book and all of its children, wiring each
+/// aggregator up to the stats of the books and portfolios directly beneath it
+fn start_book(
+    book: &BookConfig,
+    margin: &MarginConfig,
+    security_cache: &'static RwLock<SecurityCache>,
+    portfolio_addr_map: &mut HashMap<String, Addr<Portfolio>>,
+) -> Addr<BookAggregator> {
+    let aggregator = BookAggregator::new(book.code.clone(), book.level, book.children()).start();
+
+    for child in &book.books {
+        start_book(child, margin, security_cache, portfolio_addr_map)
+            .do_send(SubscribeBookStats(aggregator.clone().recipient()));
+    }
+
+    for p in &book.portfolios {
+        let addr = Portfolio::new(p, margin.clone(), security_cache).start();
+        addr.do_send(SubscribePortfolioStats(aggregator.clone().recipient()));
+        portfolio_addr_map.insert(p.code.clone(), addr);
+    }
+
+    aggregator
 }
@@ -0,0 +1,40 @@
+use crate::{config::MarginConfig, models::ASSET_CLASS};
+use piston_shared::*;
+
+/// Margin required against the positions at their current marks, and how much of it `nav` covers
+pub fn margin<'a>(
+    config: &MarginConfig,
+    positions: impl Iterator<Item = &'a Position>,
+    nav: f64,
+) -> PortfolioMargin {
+    let mut initial_requirement = 0f64;
+    let mut maintenance_requirement = 0f64;
+    let mut gross = 0f64;
+    for p in positions {
+        let rates = config.rates(p.security.groups.get(ASSET_CLASS));
+        let market_value = p.market_value().abs();
+        initial_requirement += market_value * rates.initial;
+        maintenance_requirement += market_value * rates.maintenance;
+        gross += market_value;
+    }
+
+    let state = if nav < maintenance_requirement {
+        MarginState::MarginCall
+    } else if nav < initial_requirement {
+        MarginState::BelowInitial
+    } else {
+        MarginState::Healthy
+    };
+
+    PortfolioMargin {
+        initial_requirement,
+        maintenance_requirement,
+        initial_excess: nav - initial_requirement,
+        maintenance_excess: nav - maintenance_requirement,
+        leverage: match nav > 0f64 {
+            true => gross / nav,
+            false => 0f64,
+        },
+        state,
+    }
+}
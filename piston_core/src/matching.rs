@@ -0,0 +1,156 @@
+use crate::{
+    config::MatchingConfig,
+    models::{Flush, Tick},
+    orders::{FillOrder, OrderManager, OrderUpdated},
+};
+use actix::prelude::*;
+use log::{debug, info, warn};
+use piston_shared::*;
+use std::{cmp::Ordering, collections::HashMap};
+
+const BPS: f64 = 10_000f64;
+
+/// Simulated exchange. Every tick quotes a bid and ask around the tick price, and working orders
+/// in the security are matched against that quote in price-time priority. Each side of the quote
+/// only has `quote_size` available per tick, so large orders are partially filled over several
+/// ticks, and every fill pays slippage in proportion to how much of the quote has been taken
+pub struct MatchingEngine {
+    config: MatchingConfig,
+    order_manager: Addr<OrderManager>,
+    /// Working orders per security, with the fills already sent for them counted as filled
+    books: HashMap<SecurityId, Vec<Order>>,
+}
+
+impl MatchingEngine {
+    pub fn new(config: MatchingConfig, order_manager: Addr<OrderManager>) -> Self {
+        Self {
+            config,
+            order_manager,
+            books: HashMap::default(),
+        }
+    }
+
+    /// Match one side of a security's book against its quote, returning the fills
+    fn match_side(&mut self, security_id: SecurityId, side: Side, quote: f64) -> Vec<Fill> {
+        let Some(book) = self.books.get_mut(&security_id) else {
+            return vec![];
+        };
+
+        let mut orders: Vec<_> = book.iter_mut().filter(|o| o.side == side).collect();
+        orders.sort_by(|a, b| priority(a, b, side));
+
+        let quote_size = f64::from(self.config.quote_size);
+        let mut taken = 0;
+        let mut fills = vec![];
+        for order in orders {
+            let available = self.config.quote_size - taken;
+            if available == 0 {
+                break;
+            }
+
+            let quantity = order.remaining_quantity().min(available);
+            // Average slippage over the slice of the quote this fill takes
+            let impact = self.config.impact_bps / BPS
+                * (f64::from(taken) + f64::from(quantity) / 2f64)
+                / quote_size;
+            let price = match side {
+                Side::Buy => quote * (1f64 + impact),
+                Side::Sell => quote * (1f64 - impact),
+            };
+            let price = match (order.order_type, side) {
+                (OrderType::Market, _) => price,
+                (OrderType::Limit(limit), Side::Buy) if quote <= limit => price.min(limit),
+                (OrderType::Limit(limit), Side::Sell) if quote >= limit => price.max(limit),
+                // Orders are in priority order, so no later order can trade either
+                (OrderType::Limit(_), _) => break,
+            };
+
+            order.filled_quantity += quantity;
+            taken += quantity;
+            fills.push(Fill {
+                order_id: order.id,
+                quantity,
+                price,
+            });
+        }
+
+        book.retain(|o| o.remaining_quantity() > 0);
+        fills
+    }
+}
+
+/// Market orders first, then the most aggressive limit, then the oldest order
+fn priority(a: &Order, b: &Order, side: Side) -> Ordering {
+    let limit = |o: &Order| match (o.order_type, side) {
+        (OrderType::Market, Side::Buy) => f64::INFINITY,
+        (OrderType::Market, Side::Sell) => f64::NEG_INFINITY,
+        (OrderType::Limit(limit), _) => limit,
+    };
+    let by_price = match side {
+        Side::Buy => limit(b).total_cmp(&limit(a)),
+        Side::Sell => limit(a).total_cmp(&limit(b)),
+    };
+
+    by_price.then(a.id.cmp(&b.id))
+}
+
+impl Actor for MatchingEngine {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("Started MatchingEngine with {:?}", self.config);
+    }
+}
+
+impl Handler<OrderUpdated> for MatchingEngine {
+    type Result = ();
+
+    fn handle(&mut self, msg: OrderUpdated, _: &mut Self::Context) -> Self::Result {
+        let order = msg.0;
+        let book = self.books.entry(order.security.id).or_default();
+        let existing = book.iter_mut().find(|o| o.id == order.id);
+
+        match (existing, order.status.is_working()) {
+            // Fills sent by this engine may not have been booked yet, so only ever move forward
+            (Some(o), true) => o.filled_quantity = o.filled_quantity.max(order.filled_quantity),
+            (None, true) if order.status == OrderStatus::New => book.push(order),
+            // A late update for an order this engine has already filled
+            (None, true) => {}
+            (_, false) => book.retain(|o| o.id != order.id),
+        }
+    }
+}
+
+impl Handler<Flush> for MatchingEngine {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _: &mut Self::Context) -> Self::Result {}
+}
+
+impl Handler<Tick> for MatchingEngine {
+    type Result = ();
+
+    fn handle(&mut self, msg: Tick, ctx: &mut Self::Context) -> Self::Result {
+        let half_spread = self.config.spread_bps / BPS / 2f64;
+        let ask = msg.price * (1f64 + half_spread);
+        let bid = msg.price * (1f64 - half_spread);
+
+        let mut fills = self.match_side(msg.security_id, Side::Buy, ask);
+        fills.extend(self.match_side(msg.security_id, Side::Sell, bid));
+
+        for fill in fills {
+            debug!("Matched {:?}", fill);
+            // Later ticks wait for the fill to be booked, so a flush is only answered after it
+            ctx.wait(
+                self.order_manager
+                    .send(FillOrder(fill))
+                    .into_actor(self)
+                    .map(|result, _, _| match result {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => warn!("Matched fill was not booked, {}", e),
+                        Err(e) => warn!("Failed to send matched fill, {}", e),
+                    }),
+            );
+        }
+    }
+}
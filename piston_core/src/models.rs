@@ -1,10 +1,24 @@
 use actix::Message;
 use lazy_static::lazy_static;
+use piston_ipc::messages::{PnlPoint, PortfolioSnapshot};
 use piston_shared::*;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 
 use crate::security_cache::SecurityCache;
 
+/// Position ids are shared by everything that opens positions, so they never collide
+static NEXT_POSITION_ID: AtomicU32 = AtomicU32::new(0);
+
+pub fn next_position_id() -> PositionId {
+    NEXT_POSITION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 // TODO - probably should be refactored to be in shared
 #[derive(Debug, Clone)]
 pub struct Portfolio {
@@ -15,7 +29,7 @@ pub struct Portfolio {
     pub trade_count: u32,
 }
 
-#[derive(Message, Debug)]
+#[derive(Message, Debug, Clone)]
 #[rtype(result = "()")]
 pub struct Tick {
     pub security_id: SecurityId,
@@ -23,7 +37,7 @@ pub struct Tick {
 }
 
 #[derive(Message, Debug)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<(), TradeRejection>")]
 pub struct Trade {
     pub portfolio_code: String,
     pub trade_type: TradeType,
@@ -33,43 +47,97 @@ pub struct Trade {
 pub enum TradeType {
     Open(Position),
     Close(PositionId),
+    /// Sell `size` of a security at `price`, closing the oldest positions first
+    Reduce {
+        security_id: SecurityId,
+        size: u32,
+        price: f64,
+    },
 }
 
+/// Pay a dividend of `per_share` on every share of the security held, returning the total paid
+#[derive(Message, Debug)]
+#[rtype(result = "f64")]
+pub struct PayDividend {
+    pub security_id: SecurityId,
+    pub per_share: f64,
+}
+
+/// The portfolio's stats as of now, without ending the current stats cycle
+#[derive(Message, Debug)]
+#[rtype(result = "PortfolioStats")]
+pub struct GetSnapshot;
+
+/// The stats last published as a delta, numbered so the deltas after them can be applied. Before
+/// the first delta the current stats are published as number zero
+#[derive(Message, Debug)]
+#[rtype(result = "PortfolioSnapshot")]
+pub struct Resync;
+
+/// A position marked at the latest price, if the portfolio holds it
+#[derive(Message, Debug)]
+#[rtype(result = "Option<Position>")]
+pub struct GetPosition(pub PositionId);
+
+/// The portfolio's PnL at the end of each of its recent stats cycles, oldest first
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<PnlPoint>")]
+pub struct GetPnlHistory;
+
+/// Freeze a portfolio so it rejects new positions, flatten it, or resume trading. Flattening
+/// fails with the first of its closing trades that was rejected
+#[derive(Message, Debug)]
+#[rtype(result = "Result<TradingState, TradeRejection>")]
+pub struct KillSwitch(pub KillSwitchAction);
+
+/// Answered once the actor has handled every message sent to it before, and had the replies to
+/// the requests those messages made. Lets a backtest step through the actor chain in order
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Flush;
+
 // TODO - these should be fed through into some global cache
 lazy_static! {
-    pub static ref AAPL: Security = Security {
-        ticker: String::from("AAPL"),
-        id: 0
-    };
-    pub static ref TSLA: Security = Security {
-        ticker: String::from("TSLA"),
-        id: 1
-    };
+    pub static ref AAPL: Security = Security::new(0, "AAPL");
+    pub static ref TSLA: Security = Security::new(1, "TSLA");
     pub static ref SECURITY_UNIVERSE: Vec<Security> = vec![
-        Security { id: 1, ticker: "AAPL".to_string() }, // Apple Inc.
-        Security { id: 2, ticker: "MSFT".to_string() }, // Microsoft Corporation
-        Security { id: 3, ticker: "AMZN".to_string() }, // Amazon.com, Inc.
-        Security { id: 4, ticker: "GOOGL".to_string() }, // Alphabet Inc. (Google)
-        Security { id: 5, ticker: "FB".to_string() },    // Facebook, Inc.
-        Security { id: 6, ticker: "BRK.A".to_string() }, // Berkshire Hathaway Inc.
-        Security { id: 7, ticker: "V".to_string() },     // Visa Inc.
-        Security { id: 8, ticker: "TSLA".to_string() },  // Tesla, Inc.
-        Security { id: 9, ticker: "JNJ".to_string() },   // Johnson & Johnson
-        Security { id: 10, ticker: "WMT".to_string() },  // Walmart Inc.
-        Security { id: 11, ticker: "JPM".to_string() },  // JPMorgan Chase & Co.
-        Security { id: 12, ticker: "MA".to_string() },   // Mastercard Incorporated
-        Security { id: 13, ticker: "PG".to_string() },   // The Procter & Gamble Company
-        Security { id: 14, ticker: "UNH".to_string() },  // UnitedHealth Group Incorporated
-        Security { id: 15, ticker: "DIS".to_string() },  // The Walt Disney Company
-        Security { id: 16, ticker: "NVDA".to_string() }, // NVIDIA Corporation
-        Security { id: 17, ticker: "HD".to_string() },   // The Home Depot, Inc.
-        Security { id: 18, ticker: "PYPL".to_string() }, // PayPal Holdings, Inc.
-        Security { id: 19, ticker: "BAC".to_string() },  // Bank of America Corporation
-        Security { id: 20, ticker: "VZ".to_string() },   // Verizon Communications Inc.
-        Security { id: 21, ticker: "ADBE".to_string() }, // Adobe Inc.
-        Security { id: 22, ticker: "CMCSA".to_string() },// Comcast Corporation
-        Security { id: 23, ticker: "NFLX".to_string() }, // Netflix, Inc.
-        Security { id: 24, ticker: "KO".to_string() },   // The Coca-Cola Company
-        Security { id: 25, ticker: "NKE".to_string() },  // NIKE, Inc.
+        equity(1, "AAPL", "Technology", "US"), // Apple Inc.
+        equity(2, "MSFT", "Technology", "US"), // Microsoft Corporation
+        equity(3, "AMZN", "Consumer Discretionary", "US"), // Amazon.com, Inc.
+        equity(4, "GOOGL", "Communication Services", "US"), // Alphabet Inc. (Google)
+        equity(5, "FB", "Communication Services", "US"), // Facebook, Inc.
+        equity(6, "BRK.A", "Financials", "US"), // Berkshire Hathaway Inc.
+        equity(7, "V", "Financials", "US"), // Visa Inc.
+        equity(8, "TSLA", "Consumer Discretionary", "US"), // Tesla, Inc.
+        equity(9, "JNJ", "Health Care", "US"), // Johnson & Johnson
+        equity(10, "WMT", "Consumer Staples", "US"), // Walmart Inc.
+        equity(11, "JPM", "Financials", "US"), // JPMorgan Chase & Co.
+        equity(12, "MA", "Financials", "US"), // Mastercard Incorporated
+        equity(13, "PG", "Consumer Staples", "US"), // The Procter & Gamble Company
+        equity(14, "UNH", "Health Care", "US"), // UnitedHealth Group Incorporated
+        equity(15, "DIS", "Communication Services", "US"), // The Walt Disney Company
+        equity(16, "NVDA", "Technology", "US"), // NVIDIA Corporation
+        equity(17, "HD", "Consumer Discretionary", "US"), // The Home Depot, Inc.
+        equity(18, "PYPL", "Financials", "US"), // PayPal Holdings, Inc.
+        equity(19, "BAC", "Financials", "US"), // Bank of America Corporation
+        equity(20, "VZ", "Communication Services", "US"), // Verizon Communications Inc.
+        equity(21, "ADBE", "Technology", "US"), // Adobe Inc.
+        equity(22, "CMCSA", "Communication Services", "US"), // Comcast Corporation
+        equity(23, "NFLX", "Communication Services", "US"), // Netflix, Inc.
+        equity(24, "KO", "Consumer Staples", "US"), // The Coca-Cola Company
+        equity(25, "NKE", "Consumer Discretionary", "US"), // NIKE, Inc.
     ];
 }
+
+pub const SECTOR: &str = "sector";
+pub const COUNTRY: &str = "country";
+pub const CURRENCY: &str = "currency";
+pub const ASSET_CLASS: &str = "asset_class";
+
+fn equity(id: SecurityId, ticker: &str, sector: &str, country: &str) -> Security {
+    Security::new(id, ticker)
+        .with_group(SECTOR, sector)
+        .with_group(COUNTRY, country)
+        .with_group(CURRENCY, "USD")
+        .with_group(ASSET_CLASS, "Equity")
+}
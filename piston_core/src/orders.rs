@@ -0,0 +1,313 @@
+use crate::{
+    models::{next_position_id, Trade, TradeType},
+    portfolio::Portfolio,
+    security_cache::SecurityCache,
+    stats::PortfolioStatsPublished,
+};
+use actix::prelude::*;
+use log::{debug, info, warn};
+use piston_ipc::{messages::IpcMessage, IpcWriter};
+use piston_shared::*;
+use std::{collections::HashMap, sync::RwLock};
+
+/// Tracks every order from submission until it is filled, cancelled or rejected, booking each
+/// fill into its portfolio as a `Trade`. Buy fills open a new position, sell fills reduce the
+/// portfolio's existing positions in the security
+pub struct OrderManager {
+    portfolios: HashMap<String, Addr<Portfolio>>,
+    security_cache: &'static RwLock<SecurityCache>,
+    orders: HashMap<OrderId, Order>,
+    next_order_id: OrderId,
+    subscribers: Vec<Recipient<OrderUpdated>>,
+
+    ipc_writer: IpcWriter,
+}
+
+impl OrderManager {
+    pub fn new(
+        portfolios: HashMap<String, Addr<Portfolio>>,
+        security_cache: &'static RwLock<SecurityCache>,
+    ) -> Self {
+        Self {
+            portfolios,
+            security_cache,
+            orders: HashMap::default(),
+            next_order_id: 0,
+            subscribers: vec![],
+
+            ipc_writer: IpcWriter::new(),
+        }
+    }
+
+    /// Check the fill can be applied to its order and build the trade that books it
+    fn fill_trade(&self, fill: &Fill) -> Result<(Addr<Portfolio>, Trade), String> {
+        let order = self
+            .orders
+            .get(&fill.order_id)
+            .ok_or_else(|| format!("Unknown order {}", fill.order_id))?;
+
+        if !order.status.is_working() {
+            return Err(format!("Order {} is {:?}", order.id, order.status));
+        }
+        if !fill.price.is_finite() || fill.price <= 0f64 {
+            return Err(format!("Invalid fill price {}", fill.price));
+        }
+        if fill.quantity == 0 || fill.quantity > order.remaining_quantity() {
+            return Err(format!(
+                "Fill of {} does not fit the {} remaining on order {}",
+                fill.quantity,
+                order.remaining_quantity(),
+                order.id
+            ));
+        }
+        if let OrderType::Limit(limit) = order.order_type {
+            let through_limit = match order.side {
+                Side::Buy => fill.price > limit,
+                Side::Sell => fill.price < limit,
+            };
+            if through_limit {
+                return Err(format!(
+                    "Fill price {} is through the limit {} of order {}",
+                    fill.price, limit, order.id
+                ));
+            }
+        }
+
+        let trade_type = match order.side {
+            Side::Buy => TradeType::Open(Position {
+                id: next_position_id(),
+                security: order.security.clone(),
+                cost_basis: f64::from(fill.quantity) * fill.price,
+                size: fill.quantity,
+                unrealized_pnl: 0f64,
+            }),
+            Side::Sell => TradeType::Reduce {
+                security_id: order.security.id,
+                size: fill.quantity,
+                price: fill.price,
+            },
+        };
+
+        Ok((
+            self.portfolios[&order.portfolio_code].clone(),
+            Trade {
+                portfolio_code: order.portfolio_code.clone(),
+                trade_type,
+            },
+        ))
+    }
+
+    /// Working orders for a portfolio, with what they would add to its exposure if filled.
+    /// Limit orders are valued at their limit and market orders at the latest price
+    fn portfolio_orders(&self, code: &str) -> PortfolioOrders {
+        let cache = self
+            .security_cache
+            .read()
+            .expect("could not read security cache");
+
+        let mut orders: Vec<_> = self
+            .orders
+            .values()
+            .filter(|o| o.portfolio_code == code && o.status.is_working())
+            .cloned()
+            .collect();
+        orders.sort_by_key(|o| o.id);
+
+        let mut buy_exposure = 0f64;
+        let mut sell_exposure = 0f64;
+        for order in &orders {
+            let price = match order.order_type {
+                OrderType::Limit(limit) => limit,
+                OrderType::Market => cache.get_latest_price(order.security.id).unwrap_or(0f64),
+            };
+            let value = f64::from(order.remaining_quantity()) * price;
+            match order.side {
+                Side::Buy => buy_exposure += value,
+                Side::Sell => sell_exposure += value,
+            }
+        }
+
+        PortfolioOrders {
+            code: code.to_string(),
+            orders,
+            buy_exposure,
+            sell_exposure,
+        }
+    }
+
+    fn publish(&mut self, order: &Order) {
+        debug!("Order update, {:?}", order);
+        for sub in &self.subscribers {
+            sub.do_send(OrderUpdated(order.clone()));
+        }
+        self.ipc_writer
+            .send(&IpcMessage::OrderUpdate(order.clone()));
+    }
+}
+
+impl Actor for OrderManager {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("Started OrderManager");
+    }
+}
+
+/// Published whenever an order is submitted or changes state
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "()")]
+pub struct OrderUpdated(pub Order);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeOrders(pub Recipient<OrderUpdated>);
+
+impl Handler<SubscribeOrders> for OrderManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeOrders, _: &mut Self::Context) -> Self::Result {
+        self.subscribers.push(msg.0);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Order, String>")]
+pub struct SubmitOrder(pub NewOrder);
+
+impl Handler<SubmitOrder> for OrderManager {
+    type Result = Result<Order, String>;
+
+    fn handle(&mut self, msg: SubmitOrder, _: &mut Self::Context) -> Self::Result {
+        let new = msg.0;
+        if !self.portfolios.contains_key(&new.portfolio_code) {
+            return Err(format!("Unknown portfolio {}", new.portfolio_code));
+        }
+        if new.quantity == 0 {
+            return Err(String::from("Order quantity must be positive"));
+        }
+        if let OrderType::Limit(limit) = new.order_type {
+            if !limit.is_finite() || limit <= 0f64 {
+                return Err(format!("Limit price {} must be positive", limit));
+            }
+        }
+        let security = self
+            .security_cache
+            .read()
+            .expect("could not read security cache")
+            .get_security(new.security_id)
+            .ok_or_else(|| format!("Unknown security {}", new.security_id))?;
+
+        let order = Order {
+            id: self.next_order_id,
+            portfolio_code: new.portfolio_code,
+            security,
+            side: new.side,
+            order_type: new.order_type,
+            quantity: new.quantity,
+            filled_quantity: 0,
+            average_fill_price: 0f64,
+            status: OrderStatus::New,
+            reject_reason: None,
+        };
+        self.next_order_id += 1;
+        self.orders.insert(order.id, order.clone());
+        self.publish(&order);
+
+        Ok(order)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Order, String>")]
+pub struct CancelOrder(pub OrderId);
+
+impl Handler<CancelOrder> for OrderManager {
+    type Result = Result<Order, String>;
+
+    fn handle(&mut self, msg: CancelOrder, _: &mut Self::Context) -> Self::Result {
+        let order = self
+            .orders
+            .get_mut(&msg.0)
+            .ok_or_else(|| format!("Unknown order {}", msg.0))?;
+        if !order.status.is_working() {
+            return Err(format!("Order {} is {:?}", order.id, order.status));
+        }
+        order.status = OrderStatus::Cancelled;
+
+        let order = order.clone();
+        self.publish(&order);
+
+        Ok(order)
+    }
+}
+
+/// Book an execution against a working order
+#[derive(Message)]
+#[rtype(result = "Result<Order, String>")]
+pub struct FillOrder(pub Fill);
+
+impl Handler<FillOrder> for OrderManager {
+    /// Atomic so no other fill or cancel can touch the order while its trade is being booked
+    type Result = AtomicResponse<Self, Result<Order, String>>;
+
+    fn handle(&mut self, msg: FillOrder, _: &mut Self::Context) -> Self::Result {
+        let fill = msg.0;
+        let (portfolio, trade) = match self.fill_trade(&fill) {
+            Ok(t) => t,
+            Err(e) => return AtomicResponse::new(Box::pin(fut::ready(Err(e)))),
+        };
+
+        AtomicResponse::new(Box::pin(portfolio.send(trade).into_actor(self).map(
+            move |result, act, _| {
+                let order = act
+                    .orders
+                    .get_mut(&fill.order_id)
+                    .expect("Order was checked before booking");
+
+                match result {
+                    Ok(Ok(())) => {
+                        let filled = order.filled_quantity + fill.quantity;
+                        order.average_fill_price = (order.average_fill_price
+                            * f64::from(order.filled_quantity)
+                            + fill.price * f64::from(fill.quantity))
+                            / f64::from(filled);
+                        order.filled_quantity = filled;
+                        order.status = match order.remaining_quantity() {
+                            0 => OrderStatus::Filled,
+                            _ => OrderStatus::PartiallyFilled,
+                        };
+                    }
+                    // Fills already booked stand, so only the remainder is cancelled
+                    Ok(Err(rejection)) if order.filled_quantity > 0 => {
+                        warn!(
+                            "Order {} cancelled after a partial fill, {}",
+                            order.id, rejection
+                        );
+                        order.status = OrderStatus::Cancelled;
+                        order.reject_reason = Some(rejection.to_string());
+                    }
+                    Ok(Err(rejection)) => {
+                        warn!("Order {} rejected, {}", order.id, rejection);
+                        order.status = OrderStatus::Rejected;
+                        order.reject_reason = Some(rejection.to_string());
+                    }
+                    Err(e) => return Err(format!("Failed to book fill, {}", e)),
+                }
+
+                let order = order.clone();
+                act.publish(&order);
+
+                Ok(order)
+            },
+        )))
+    }
+}
+
+impl Handler<PortfolioStatsPublished> for OrderManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: PortfolioStatsPublished, _: &mut Self::Context) -> Self::Result {
+        let orders = self.portfolio_orders(&msg.0.code);
+        self.ipc_writer.send(&IpcMessage::PortfolioOrders(orders));
+    }
+}
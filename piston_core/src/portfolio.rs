@@ -1,9 +1,31 @@
-use crate::{models::*, security_cache::SecurityCache, stats::PortfolioStatsEvent};
-use actix::{Actor, Context, Handler};
-use log::{debug, info};
-use piston_ipc::{messages::IpcMessage, IpcWriter};
+use crate::{
+    attribution::PnlAttributor,
+    cash::CashLedger,
+    config::{LimitsConfig, MarginConfig, PortfolioConfig},
+    exposure::exposure,
+    limits::check_limits,
+    margin::margin,
+    models::*,
+    scenario::{run_scenario, RunScenarios},
+    security_cache::SecurityCache,
+    stats::{PortfolioStatsEvent, PortfolioStatsPublished, SubscribePortfolioStats},
+};
+use actix::{Actor, Context, Handler, Message, MessageResult, Recipient};
+use log::{debug, info, warn};
+use piston_ipc::{
+    delta::changes,
+    messages::{IpcMessage, PnlPoint, PortfolioDelta, PortfolioSnapshot, TradeReport},
+    IpcWriter,
+};
 use piston_shared::*;
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Stats cycles of PnL history kept, an hour at one cycle a second
+const PNL_HISTORY_LENGTH: usize = 3600;
 
 #[derive(Debug)]
 pub struct Portfolio {
@@ -12,102 +34,587 @@ pub struct Portfolio {
     pnl: f64,
     security_cache: &'static RwLock<SecurityCache>,
     trade_count: u32,
+    rejected_trade_count: u32,
+    limits: LimitsConfig,
+    trading_state: TradingState,
+    /// Positions closed by flattening, whose scheduled closes are no longer rejections
+    flattened: HashSet<PositionId>,
+    subscribers: Vec<Recipient<PortfolioStatsPublished>>,
+    trade_subscribers: Vec<Recipient<TradeBooked>>,
+    attributor: PnlAttributor,
+    ledger: CashLedger,
+    margin: MarginConfig,
+    /// Deltas are taken from the stats last published
+    published: Option<PortfolioSnapshot>,
+    pnl_history: VecDeque<PnlPoint>,
 
     ipc_writer: IpcWriter,
 }
 
 impl Portfolio {
-    pub fn new(code: String, security_cache: &'static RwLock<SecurityCache>) -> Self {
+    pub fn new(
+        config: &PortfolioConfig,
+        margin: MarginConfig,
+        security_cache: &'static RwLock<SecurityCache>,
+    ) -> Self {
         Self {
-            code,
+            code: config.code.clone(),
             security_cache,
             positions: HashMap::default(),
             pnl: 0f64,
             trade_count: 0,
+            rejected_trade_count: 0,
+            limits: config.limits.clone(),
+            trading_state: TradingState::Active,
+            flattened: HashSet::default(),
+            subscribers: vec![],
+            trade_subscribers: vec![],
+            attributor: PnlAttributor::default(),
+            ledger: CashLedger::new(config.cash.clone()),
+            margin,
+            published: None,
+            pnl_history: VecDeque::new(),
 
-            ipc_writer: IpcWriter::new().expect("Failed to create ipc writer"),
+            ipc_writer: IpcWriter::new(),
         }
     }
 
-    pub fn recalculate_positions(&mut self) {
-        for p in self.positions.values_mut() {
-            let cache = self
+    fn open_position(&mut self, pos: Position) -> Result<(), RejectReason> {
+        if self.trading_state == TradingState::Frozen {
+            return Err(RejectReason::Frozen);
+        }
+
+        let cache = self
+            .security_cache
+            .read()
+            .expect("could not read security cache");
+        check_limits(&self.limits, self.positions.values(), &pos, |id| {
+            cache.get_latest_price(id)
+        })?;
+        self.ledger
+            .check_buying_power(&pos.security, pos.cost_basis)?;
+        self.check_initial_margin(&pos)?;
+
+        debug!(
+            "{} has entered a new {} position",
+            self.code, &pos.security.ticker
+        );
+        self.publish_trade(
+            &pos.security,
+            Side::Buy,
+            pos.size,
+            pos.cost_basis / f64::from(pos.size),
+            0f64,
+        );
+        self.ledger.buy(&pos.security, pos.cost_basis);
+        self.positions.insert(pos.id, pos);
+        self.trade_count += 1;
+
+        Ok(())
+    }
+
+    /// Check NAV would still cover the initial margin on every position, the new one included,
+    /// once it has been bought. Positions are taken at their last mark
+    fn check_initial_margin(&self, pos: &Position) -> Result<(), RejectReason> {
+        let nav = self
+            .ledger
+            .balances(self.positions.values())
+            .iter()
+            .map(|c| c.nav)
+            .sum::<f64>()
+            - self.ledger.fee(pos.cost_basis);
+        let required = margin(
+            &self.margin,
+            self.positions.values().chain(std::iter::once(pos)),
+            nav,
+        )
+        .initial_requirement;
+
+        match required <= nav {
+            true => Ok(()),
+            false => Err(RejectReason::InitialMargin { required, nav }),
+        }
+    }
+
+    /// Close the position at the latest price, realizing its PnL
+    fn close_position(&mut self, pos_id: PositionId) -> Result<(), RejectReason> {
+        let mut p = self
+            .positions
+            .remove(&pos_id)
+            .ok_or(RejectReason::UnknownPosition(pos_id))?;
+
+        mark_to_market(
+            &self
+                .security_cache
+                .read()
+                .expect("could not read security cache"),
+            &mut p,
+        );
+        self.pnl += p.unrealized_pnl;
+        self.ledger.sell(&p.security, p.market_value());
+        self.attributor.on_close(&p);
+        self.trade_count += 1;
+        self.publish_trade(
+            &p.security,
+            Side::Sell,
+            p.size,
+            p.market_value() / f64::from(p.size),
+            p.unrealized_pnl,
+        );
+        debug!(
+            "{} has closed their {} position",
+            self.code, p.security.ticker
+        );
+
+        Ok(())
+    }
+
+    /// Sell `size` of a security at `price`, closing the oldest positions first and splitting the
+    /// last one if only part of it is sold
+    fn reduce_position(
+        &mut self,
+        security_id: SecurityId,
+        size: u32,
+        price: f64,
+    ) -> Result<(), RejectReason> {
+        let mut lots: Vec<_> = self
+            .positions
+            .values()
+            .filter(|p| p.security.id == security_id)
+            .map(|p| p.id)
+            .collect();
+        lots.sort();
+
+        let held: u32 = lots.iter().map(|id| self.positions[id].size).sum();
+        if held < size {
+            return Err(RejectReason::InsufficientPosition {
+                security_id,
+                held,
+                requested: size,
+            });
+        }
+
+        let mut remaining = size;
+        let mut realized = 0f64;
+        let mut security = None;
+        for id in lots {
+            if remaining == 0 {
+                break;
+            }
+
+            let lot = self.positions.get_mut(&id).expect("Lot was just found");
+            let sold = remaining.min(lot.size);
+            let fraction = f64::from(sold) / f64::from(lot.size);
+            let cost_basis = lot.cost_basis * fraction;
+            let closed = Position {
+                size: sold,
+                cost_basis,
+                unrealized_pnl: f64::from(sold) * price - cost_basis,
+                ..lot.clone()
+            };
+
+            lot.size -= sold;
+            lot.cost_basis -= cost_basis;
+            lot.unrealized_pnl -= lot.unrealized_pnl * fraction;
+            if lot.size == 0 {
+                self.positions.remove(&id);
+            }
+
+            self.pnl += closed.unrealized_pnl;
+            self.attributor.on_reduce(&closed, fraction);
+            realized += closed.unrealized_pnl;
+            security = Some(closed.security);
+            remaining -= sold;
+        }
+
+        self.trade_count += 1;
+        if let Some(security) = security {
+            self.ledger.sell(&security, f64::from(size) * price);
+            self.publish_trade(&security, Side::Sell, size, price, realized);
+        }
+        debug!(
+            "{} has sold {} of security {}",
+            self.code, size, security_id
+        );
+
+        Ok(())
+    }
+
+    /// Book a trade, counting and logging it if it is rejected. A limit breach pulls the kill
+    /// switch when the limits set a `breach_action`
+    fn book(&mut self, trade_type: TradeType) -> Result<(), TradeRejection> {
+        let result = match trade_type {
+            TradeType::Open(pos) => self.open_position(pos),
+            TradeType::Close(pos_id) => self.close_position(pos_id),
+            TradeType::Reduce {
+                security_id,
+                size,
+                price,
+            } => self.reduce_position(security_id, size, price),
+        };
+
+        result.map_err(|reason| {
+            self.rejected_trade_count += 1;
+            let rejection = TradeRejection {
+                portfolio_code: self.code.clone(),
+                reason,
+            };
+            warn!("{}", rejection);
+
+            if let Some(action) = self.limits.breach_action {
+                if rejection.reason.is_limit_breach() {
+                    warn!("{} breached a limit, pulling kill switch", self.code);
+                    if let Err(e) = self.kill_switch(action) {
+                        warn!("{} kill switch failed, {}", self.code, e);
+                    }
+                }
+            }
+            rejection
+        })
+    }
+
+    fn kill_switch(&mut self, action: KillSwitchAction) -> Result<TradingState, TradeRejection> {
+        warn!("{} kill switch: {:?}", self.code, action);
+        match action {
+            KillSwitchAction::Freeze => self.trading_state = TradingState::Frozen,
+            KillSwitchAction::Flatten => self.flatten()?,
+            KillSwitchAction::Resume => self.trading_state = TradingState::Active,
+        }
+
+        Ok(self.trading_state)
+    }
+
+    /// Freeze the portfolio and sell everything it holds at the latest prices, booked as trades
+    /// like any other. Every security is tried, and the first rejection is returned
+    fn flatten(&mut self) -> Result<(), TradeRejection> {
+        self.trading_state = TradingState::Frozen;
+
+        let mut held = BTreeMap::new();
+        for p in self.positions.values() {
+            *held.entry(p.security.id).or_insert(0u32) += p.size;
+        }
+        let before: Vec<_> = self.positions.keys().copied().collect();
+
+        let mut result = Ok(());
+        for (security_id, size) in held {
+            let price = self
                 .security_cache
                 .read()
-                .expect("could not read security cache");
+                .expect("could not read security cache")
+                .get_latest_price(security_id);
+            let booked = match price {
+                Some(price) => self.book(TradeType::Reduce {
+                    security_id,
+                    size,
+                    price,
+                }),
+                None => {
+                    self.rejected_trade_count += 1;
+                    Err(TradeRejection {
+                        portfolio_code: self.code.clone(),
+                        reason: RejectReason::NoPrice(security_id),
+                    })
+                }
+            };
+            result = result.and(booked);
+        }
+
+        self.flattened.extend(
+            before
+                .into_iter()
+                .filter(|id| !self.positions.contains_key(id)),
+        );
+        result
+    }
+
+    fn publish_trade(
+        &mut self,
+        security: &Security,
+        side: Side,
+        size: u32,
+        price: f64,
+        realized_pnl: f64,
+    ) {
+        for sub in &self.trade_subscribers {
+            sub.do_send(TradeBooked {
+                portfolio_code: self.code.clone(),
+                security: security.clone(),
+                side,
+                size,
+                price,
+                realized_pnl,
+            });
+        }
+
+        self.ipc_writer.send(&IpcMessage::Trade(TradeReport {
+            portfolio_code: self.code.clone(),
+            security_id: security.id,
+            ticker: security.ticker.clone(),
+            side,
+            size,
+            price,
+            realized_pnl,
+        }));
+    }
+
+    /// Mark every position and build the portfolio's stats. Ending the cycle restarts PnL
+    /// attribution, otherwise it covers everything since the previous cycle ended
+    fn stats(&mut self, end_cycle: bool) -> PortfolioStats {
+        self.recalculate_positions();
+        let unrealized_pnl = self
+            .positions
+            .iter()
+            .fold(0f64, |acc, p| acc + p.1.unrealized_pnl);
+
+        let cash = self.ledger.balances(self.positions.values());
+        let nav = cash.iter().map(|c| c.nav).sum();
+        let attribution = match end_cycle {
+            true => self.attributor.attribute(self.positions.values()),
+            false => self.attributor.clone().attribute(self.positions.values()),
+        };
+
+        PortfolioStats {
+            code: self.code.clone(),
+            positions: self.positions.values().cloned().collect(),
+            trade_count: self.trade_count,
+            rejected_trade_count: self.rejected_trade_count,
+            trading_state: self.trading_state,
+            pnl: self.pnl,
+            unrealized_pnl,
+            attribution,
+            cash,
+            nav,
+            margin: margin(&self.margin, self.positions.values(), nav),
+            exposure: exposure(self.positions.values()),
+        }
+    }
 
-            let latest_price = cache
-                .get_latest_price(p.security.id)
-                .expect("Unknown price for secrurity");
+    pub fn recalculate_positions(&mut self) {
+        let cache = self
+            .security_cache
+            .read()
+            .expect("could not read security cache");
 
-            p.unrealized_pnl = (f64::from(p.size) * latest_price) - p.cost_basis;
+        for p in self.positions.values_mut() {
+            mark_to_market(&cache, p);
         }
     }
 }
 
+fn mark_to_market(cache: &SecurityCache, p: &mut Position) {
+    let latest_price = cache
+        .get_latest_price(p.security.id)
+        .expect("Unknown price for secrurity");
+
+    p.unrealized_pnl = (f64::from(p.size) * latest_price) - p.cost_basis;
+}
+
 impl Actor for Portfolio {
     type Context = Context<Self>;
 }
 
-impl Handler<Trade> for Portfolio {
+/// Published by a `Portfolio` to its trade subscribers for every trade it books
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "()")]
+pub struct TradeBooked {
+    pub portfolio_code: String,
+    pub security: Security,
+    pub side: Side,
+    pub size: u32,
+    pub price: f64,
+    /// PnL realized by the trade, zero when opening a position
+    pub realized_pnl: f64,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeTrades(pub Recipient<TradeBooked>);
+
+impl Handler<SubscribeTrades> for Portfolio {
     type Result = ();
 
+    fn handle(&mut self, msg: SubscribeTrades, _: &mut Self::Context) -> Self::Result {
+        self.trade_subscribers.push(msg.0);
+    }
+}
+
+impl Handler<Trade> for Portfolio {
+    type Result = Result<(), TradeRejection>;
+
     fn handle(&mut self, msg: Trade, _: &mut Self::Context) -> Self::Result {
         if msg.portfolio_code != self.code {
-            return;
+            return Ok(());
         }
 
         debug!("Got trade message, {:#?}", msg);
-        self.trade_count += 1;
-
-        match msg.trade_type {
-            TradeType::Open(pos) => {
-                debug!(
-                    "{} has entered a new {} position",
-                    self.code, &pos.security.ticker
-                );
-                self.positions.insert(pos.id, pos);
+        if let TradeType::Close(pos_id) = msg.trade_type {
+            if self.flattened.remove(&pos_id) {
+                debug!("{} position {} was already flattened", self.code, pos_id);
+                return Ok(());
             }
-            TradeType::Close(pos_id) => match self.positions.remove(&pos_id) {
-                None => panic!("Closing a position that does not exist"),
-                Some(p) => {
-                    self.pnl += p.unrealized_pnl;
-                    debug!(
-                        "{} has closed their {} position",
-                        self.code, p.security.ticker
-                    );
-                }
-            },
         }
+
+        self.book(msg.trade_type)
+    }
+}
+
+impl Handler<KillSwitch> for Portfolio {
+    type Result = Result<TradingState, TradeRejection>;
+
+    fn handle(&mut self, msg: KillSwitch, _: &mut Self::Context) -> Self::Result {
+        self.kill_switch(msg.0)
     }
 }
+
+impl Handler<PayDividend> for Portfolio {
+    type Result = f64;
+
+    fn handle(&mut self, msg: PayDividend, _: &mut Self::Context) -> Self::Result {
+        let held: Vec<_> = self
+            .positions
+            .values()
+            .filter(|p| p.security.id == msg.security_id)
+            .collect();
+        let Some(security) = held.first().map(|p| p.security.clone()) else {
+            return 0f64;
+        };
+
+        let amount = held.iter().map(|p| f64::from(p.size)).sum::<f64>() * msg.per_share;
+        self.ledger.dividend(&security, amount);
+        info!(
+            "{} received a dividend of {} on {}",
+            self.code, amount, security.ticker
+        );
+
+        amount
+    }
+}
+
 impl Handler<PortfolioStatsEvent> for Portfolio {
     type Result = ();
 
     fn handle(&mut self, _msg: PortfolioStatsEvent, _: &mut Self::Context) -> Self::Result {
-        self.recalculate_positions();
-        let unrealized_pnl = self
-            .positions
-            .iter()
-            .fold(0f64, |acc, p| acc + p.1.unrealized_pnl);
+        let stats = self.stats(true);
+        if stats.margin.state == MarginState::MarginCall {
+            warn!(
+                "{} margin call, nav {} is below the maintenance requirement of {}",
+                self.code, stats.nav, stats.margin.maintenance_requirement
+            );
+        }
 
         info!(
-            "STATS: {}, {} posititons, {} total trades, realized: {}, unrealized: {}",
+            "STATS: {}, {} posititons, {} total trades, realized: {}, unrealized: {}, nav: {}",
             self.code,
-            self.positions.len(),
+            stats.positions.len(),
             self.trade_count,
             self.pnl,
-            unrealized_pnl
+            stats.unrealized_pnl,
+            stats.nav
         );
 
+        for sub in &self.subscribers {
+            sub.do_send(PortfolioStatsPublished(stats.clone()));
+        }
+
+        if self.pnl_history.len() == PNL_HISTORY_LENGTH {
+            self.pnl_history.pop_front();
+        }
+        self.pnl_history.push_back(PnlPoint {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System clock is before the unix epoch")
+                .as_secs(),
+            pnl: stats.pnl,
+            unrealized_pnl: stats.unrealized_pnl,
+            nav: stats.nav,
+        });
+
+        let changes = changes(self.published.as_ref().map(|p| &p.stats), &stats);
+        if changes.is_empty() {
+            return;
+        }
+        let sequence = self.published.as_ref().map_or(0, |p| p.sequence) + 1;
         self.ipc_writer
-            .send(&IpcMessage::PortfolioStats(PortfolioStats {
+            .send(&IpcMessage::PortfolioDelta(PortfolioDelta {
                 code: self.code.clone(),
-                positions: self.positions.values().cloned().collect(),
-                trade_count: self.trade_count,
-                pnl: self.pnl,
-                unrealized_pnl,
-            }))
-            .expect("Failed to send portfolio stats");
+                sequence,
+                changes,
+            }));
+        self.published = Some(PortfolioSnapshot { sequence, stats });
+    }
+}
+
+impl Handler<Resync> for Portfolio {
+    type Result = MessageResult<Resync>;
+
+    fn handle(&mut self, _msg: Resync, _: &mut Self::Context) -> Self::Result {
+        if self.published.is_none() {
+            self.published = Some(PortfolioSnapshot {
+                sequence: 0,
+                stats: self.stats(false),
+            });
+        }
+
+        MessageResult(self.published.clone().expect("Stats were just published"))
+    }
+}
+
+impl Handler<GetSnapshot> for Portfolio {
+    type Result = MessageResult<GetSnapshot>;
+
+    fn handle(&mut self, _msg: GetSnapshot, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.stats(false))
+    }
+}
+
+impl Handler<GetPnlHistory> for Portfolio {
+    type Result = MessageResult<GetPnlHistory>;
+
+    fn handle(&mut self, _msg: GetPnlHistory, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.pnl_history.iter().cloned().collect())
+    }
+}
+
+impl Handler<GetPosition> for Portfolio {
+    type Result = Option<Position>;
+
+    fn handle(&mut self, msg: GetPosition, _: &mut Self::Context) -> Self::Result {
+        let cache = self
+            .security_cache
+            .read()
+            .expect("could not read security cache");
+        let p = self.positions.get_mut(&msg.0)?;
+        mark_to_market(&cache, p);
+
+        Some(p.clone())
+    }
+}
+
+impl Handler<SubscribePortfolioStats> for Portfolio {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribePortfolioStats, _: &mut Self::Context) -> Self::Result {
+        self.subscribers.push(msg.0);
+    }
+}
+
+impl Handler<RunScenarios> for Portfolio {
+    type Result = Vec<ScenarioResult>;
+
+    fn handle(&mut self, msg: RunScenarios, _: &mut Self::Context) -> Self::Result {
+        let cache = self
+            .security_cache
+            .read()
+            .expect("could not read security cache");
+        let positions: Vec<_> = self.positions.values().cloned().collect();
+
+        msg.0
+            .iter()
+            .map(|scenario| {
+                run_scenario(scenario, &self.code, &positions, |id| {
+                    cache.get_latest_price(id)
+                })
+            })
+            .collect()
     }
 }
@@ -0,0 +1,157 @@
+use crate::{config::RiskConfig, models::Tick, stats::PortfolioStatsPublished};
+use actix::prelude::*;
+use log::{debug, info, warn};
+use piston_ipc::{messages::IpcMessage, IpcWriter};
+use piston_shared::*;
+use std::collections::{HashMap, VecDeque};
+
+/// Computes historical-simulation VaR and Expected Shortfall for each portfolio's holdings,
+/// using a rolling window of per-security returns built from the tick stream
+pub struct RiskActor {
+    config: RiskConfig,
+    last_price: HashMap<SecurityId, f64>,
+    returns: HashMap<SecurityId, VecDeque<f64>>,
+    /// Tickers last left out of each portfolio's risk, so each change is only warned about once
+    excluded: HashMap<String, Vec<String>>,
+
+    ipc_writer: IpcWriter,
+}
+
+impl RiskActor {
+    pub fn new(config: RiskConfig) -> Self {
+        Self {
+            config,
+            last_price: HashMap::default(),
+            returns: HashMap::default(),
+            excluded: HashMap::default(),
+
+            ipc_writer: IpcWriter::new(),
+        }
+    }
+
+    /// Revalue the positions under each historical scenario. Scenario `i` applies the `i`th most
+    /// recent return of every security, so the result is ordered from newest to oldest
+    fn scenario_pnls(&self, positions: &[Position]) -> Vec<f64> {
+        let observations = positions
+            .iter()
+            .map(|p| self.returns.get(&p.security.id).map_or(0, |r| r.len()))
+            .min()
+            .unwrap_or(0);
+
+        (0..observations)
+            .map(|i| {
+                positions
+                    .iter()
+                    .map(|p| {
+                        let returns = &self.returns[&p.security.id];
+                        p.market_value() * returns[returns.len() - 1 - i]
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Risk of the positions in securities with return history. Those without any are left out
+    /// and listed, rather than holding every scenario at zero
+    fn portfolio_risk(&self, stats: &PortfolioStats) -> PortfolioRisk {
+        let (positions, unmeasured): (Vec<_>, Vec<_>) =
+            stats.positions.iter().cloned().partition(|p| {
+                self.returns
+                    .get(&p.security.id)
+                    .is_some_and(|r| !r.is_empty())
+            });
+        let mut excluded: Vec<_> = unmeasured.into_iter().map(|p| p.security.ticker).collect();
+        excluded.sort();
+        excluded.dedup();
+
+        let mut pnls = self.scenario_pnls(&positions);
+        pnls.sort_by(|a, b| a.total_cmp(b));
+
+        PortfolioRisk {
+            code: stats.code.clone(),
+            observations: pnls.len() as u32,
+            metrics: self
+                .config
+                .confidence_levels
+                .iter()
+                .map(|confidence| risk_metric(&pnls, *confidence))
+                .collect(),
+            excluded,
+        }
+    }
+}
+
+/// `sorted_pnls` must be sorted from worst to best
+fn risk_metric(sorted_pnls: &[f64], confidence: f64) -> RiskMetric {
+    if sorted_pnls.is_empty() {
+        return RiskMetric {
+            confidence,
+            var: 0f64,
+            expected_shortfall: 0f64,
+        };
+    }
+
+    let cutoff = ((1f64 - confidence) * sorted_pnls.len() as f64).floor() as usize;
+    let cutoff = cutoff.min(sorted_pnls.len() - 1);
+    let tail = &sorted_pnls[..=cutoff];
+
+    RiskMetric {
+        confidence,
+        var: -sorted_pnls[cutoff],
+        expected_shortfall: -tail.iter().sum::<f64>() / tail.len() as f64,
+    }
+}
+
+impl Actor for RiskActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!(
+            "Started RiskActor, window {} at confidence levels {:?}",
+            self.config.window, self.config.confidence_levels
+        );
+    }
+}
+
+impl Handler<Tick> for RiskActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Tick, _: &mut Self::Context) -> Self::Result {
+        let Some(last) = self.last_price.insert(msg.security_id, msg.price) else {
+            return;
+        };
+        // A return from a price that is not positive would be infinite or meaningless
+        let r = msg.price / last - 1f64;
+        if !(last.is_finite() && last > 0f64 && r.is_finite()) {
+            return;
+        }
+
+        let returns = self.returns.entry(msg.security_id).or_default();
+        returns.push_back(r);
+        if returns.len() > self.config.window {
+            returns.pop_front();
+        }
+    }
+}
+
+impl Handler<PortfolioStatsPublished> for RiskActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PortfolioStatsPublished, _: &mut Self::Context) -> Self::Result {
+        let risk = self.portfolio_risk(&msg.0);
+        debug!("RISK: {}, {:?}", risk.code, risk.metrics);
+        if self.excluded.get(&risk.code) != Some(&risk.excluded) {
+            if !risk.excluded.is_empty() {
+                warn!(
+                    "{} risk leaves out {}, which have no return history",
+                    risk.code,
+                    risk.excluded.join(", ")
+                );
+            }
+            self.excluded
+                .insert(risk.code.clone(), risk.excluded.clone());
+        }
+
+        self.ipc_writer.send(&IpcMessage::PortfolioRisk(risk));
+    }
+}
@@ -0,0 +1,60 @@
+use actix::Message;
+use piston_shared::*;
+
+/// Revalue a portfolio under each scenario without touching its positions
+#[derive(Message)]
+#[rtype(result = "Vec<ScenarioResult>")]
+pub struct RunScenarios(pub Vec<Scenario>);
+
+/// Revalue positions at their scenario prices. `latest_price` gives the current mark for a
+/// security, and positions without one are left out
+pub fn run_scenario(
+    scenario: &Scenario,
+    portfolio_code: &str,
+    positions: &[Position],
+    latest_price: impl Fn(SecurityId) -> Option<f64>,
+) -> ScenarioResult {
+    let positions: Vec<_> = positions
+        .iter()
+        .filter_map(|p| {
+            let price = latest_price(p.security.id)?;
+            let shocked_price = shock_price(scenario, &p.security, price);
+
+            Some(PositionScenarioPnl {
+                position_id: p.id,
+                ticker: p.security.ticker.clone(),
+                price,
+                shocked_price,
+                pnl: f64::from(p.size) * (shocked_price - price),
+            })
+        })
+        .collect();
+
+    ScenarioResult {
+        scenario: scenario.name.clone(),
+        portfolio_code: portfolio_code.to_string(),
+        pnl: positions.iter().map(|p| p.pnl).sum(),
+        positions,
+    }
+}
+
+/// Apply every matching shock in order. Prices are floored at zero
+fn shock_price(scenario: &Scenario, security: &Security, price: f64) -> f64 {
+    scenario
+        .shocks
+        .iter()
+        .filter(|shock| matches(&shock.target, security))
+        .fold(price, |price, shock| match shock.shift {
+            PriceShift::Absolute(amount) => price + amount,
+            PriceShift::Relative(fraction) => price * (1f64 + fraction),
+        })
+        .max(0f64)
+}
+
+fn matches(target: &ShockTarget, security: &Security) -> bool {
+    match target {
+        ShockTarget::Security(ticker) => &security.ticker == ticker,
+        ShockTarget::Group { group, name } => security.groups.get(group) == Some(name),
+        ShockTarget::Universe => true,
+    }
+}
@@ -5,6 +5,10 @@ use actix::prelude::*;
 use actix::Context;
 use log::debug;
 use moka::sync::Cache;
+use piston_ipc::{
+    messages::{IpcMessage, PriceTick},
+    IpcWriter,
+};
 use piston_shared::*;
 use rand::{prelude::SliceRandom, thread_rng, Rng};
 
@@ -44,6 +48,10 @@ impl SecurityCache {
         self.securities.get(&id)
     }
 
+    pub fn get_securities(&self) -> Vec<Security> {
+        self.securities.iter().map(|(_, sec)| sec).collect()
+    }
+
     pub fn get_random_security(&self) -> Security {
         // TODO derive this from the count
         let mut rng = thread_rng();
@@ -66,14 +74,25 @@ impl SecurityCacheActor {
     pub fn new(security_cache: &'static RwLock<SecurityCache>) -> Self {
         Self {
             inner: security_cache,
+            subscribers: vec![],
+
+            ipc_writer: IpcWriter::new(),
         }
     }
 }
 
 pub struct SecurityCacheActor {
     inner: &'static RwLock<SecurityCache>,
+    subscribers: Vec<Recipient<Tick>>,
+
+    ipc_writer: IpcWriter,
 }
 
+/// Forward every tick to the recipient once it has been applied to the cache
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeTicks(pub Recipient<Tick>);
+
 #[derive(Message)]
 #[rtype(result = "Option<f64>")]
 pub struct GetLatestPrice(pub SecurityId);
@@ -91,9 +110,55 @@ impl Handler<Tick> for SecurityCacheActor {
 
     fn handle(&mut self, msg: Tick, _ctx: &mut Self::Context) -> Self::Result {
         debug!("got tick data! {:?}", msg);
+        let mut cache = self.inner.write().expect("failed to get the lock");
+        cache.set_last_price(msg.security_id, msg.price);
+        let ticker = cache
+            .get_security(msg.security_id)
+            .map(|s| s.ticker)
+            .unwrap_or_default();
+        drop(cache);
+
+        for sub in &self.subscribers {
+            sub.do_send(msg.clone());
+        }
+
+        self.ipc_writer.send(&IpcMessage::Tick(PriceTick {
+            security_id: msg.security_id,
+            ticker,
+            price: msg.price,
+        }));
+    }
+}
+
+impl Handler<SubscribeTicks> for SecurityCacheActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeTicks, _ctx: &mut Self::Context) -> Self::Result {
+        self.subscribers.push(msg.0);
+    }
+}
+
+impl Handler<GetLatestPrice> for SecurityCacheActor {
+    type Result = Option<f64>;
+
+    fn handle(&mut self, msg: GetLatestPrice, _ctx: &mut Self::Context) -> Self::Result {
         self.inner
-            .write()
+            .read()
             .expect("failed to get the lock")
-            .set_last_price(msg.security_id, msg.price);
+            .get_latest_price(msg.0)
+    }
+}
+
+impl Handler<GetSecurity> for SecurityCacheActor {
+    type Result = MessageResult<GetSecurity>;
+
+    fn handle(&mut self, msg: GetSecurity, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            self.inner
+                .read()
+                .expect("failed to get the lock")
+                .get_security(msg.0)
+                .expect("Unknown security"),
+        )
     }
 }
@@ -1,9 +1,12 @@
 use actix::prelude::*;
 use log::info;
+use piston_shared::PortfolioStats;
 use std::time::Duration;
 
 use crate::portfolio::Portfolio;
 
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct PortfolioStatsFeed {
     subs: Vec<Addr<Portfolio>>,
 }
@@ -19,7 +22,7 @@ impl Actor for PortfolioStatsFeed {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Started PortfolioStatsFeed");
-        ctx.run_interval(Duration::from_secs(1), move |act, _| {
+        ctx.run_interval(STATS_INTERVAL, move |act, _| {
             for sub in &act.subs {
                 sub.do_send(PortfolioStatsEvent {});
             }
@@ -30,3 +33,12 @@ impl Actor for PortfolioStatsFeed {
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct PortfolioStatsEvent;
+
+/// Published by a `Portfolio` to its subscribers after every stats cycle
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct PortfolioStatsPublished(pub PortfolioStats);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribePortfolioStats(pub Recipient<PortfolioStatsPublished>);
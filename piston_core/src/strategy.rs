@@ -0,0 +1,380 @@
+use crate::{
+    config::{Execution, PortfolioConfig, StrategyConfig, StrategyKind},
+    models::{next_position_id, Flush, Tick, Trade, TradeType, SECURITY_UNIVERSE},
+    orders::{OrderManager, SubmitOrder},
+    portfolio::Portfolio,
+    security_cache::{SecurityCacheActor, SubscribeTicks},
+    stats::{PortfolioStatsPublished, SubscribePortfolioStats},
+};
+use actix::prelude::*;
+use log::{debug, info, warn};
+use piston_shared::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// What a strategy wants done for its portfolio
+#[derive(Debug)]
+pub enum Signal {
+    /// Submit an order, to be filled by the matching engine or a reported fill
+    Order {
+        security_id: SecurityId,
+        side: Side,
+        order_type: OrderType,
+        quantity: u32,
+    },
+    /// Book a trade straight into the portfolio
+    Trade(TradeType),
+}
+
+/// A trading bot for a single portfolio. Strategies only see ticks for the securities they were
+/// configured to trade
+pub trait Strategy {
+    fn on_tick(&mut self, security: &Security, tick: &Tick) -> Vec<Signal>;
+
+    /// Called with the portfolio's stats after every stats cycle
+    fn on_stats(&mut self, _stats: &PortfolioStats) -> Vec<Signal> {
+        vec![]
+    }
+}
+
+pub fn build_strategy(kind: &StrategyKind, execution: Execution) -> Box<dyn Strategy> {
+    match *kind {
+        StrategyKind::Momentum {
+            lookback,
+            threshold,
+            quantity,
+        } => Box::new(Momentum {
+            prices: PriceHistory::new(lookback),
+            holdings: Holdings::new(execution),
+            threshold,
+            quantity,
+        }),
+        StrategyKind::MeanReversion {
+            lookback,
+            threshold,
+            quantity,
+        } => Box::new(MeanReversion {
+            prices: PriceHistory::new(lookback),
+            holdings: Holdings::new(execution),
+            threshold,
+            quantity,
+        }),
+    }
+}
+
+/// Start every strategy configured for the portfolio, wired up to the tick stream and the
+/// portfolio's stats
+pub fn start_strategies(
+    config: &PortfolioConfig,
+    portfolio: &Addr<Portfolio>,
+    security_cache_actor: &Addr<SecurityCacheActor>,
+    order_manager: &Addr<OrderManager>,
+) -> Vec<Addr<StrategyActor>> {
+    let mut strategies = vec![];
+    for strategy in &config.strategies {
+        let addr = StrategyActor::new(
+            config.code.clone(),
+            strategy,
+            &SECURITY_UNIVERSE,
+            portfolio.clone(),
+            order_manager.clone(),
+        )
+        .start();
+        security_cache_actor.do_send(SubscribeTicks(addr.clone().recipient()));
+        portfolio.do_send(SubscribePortfolioStats(addr.clone().recipient()));
+        strategies.push(addr);
+    }
+    strategies
+}
+
+/// Runs a `Strategy` against the tick stream and its portfolio's stats, sending its orders to the
+/// `OrderManager` and its trades to the portfolio
+pub struct StrategyActor {
+    portfolio_code: String,
+    strategy: Box<dyn Strategy>,
+    /// Securities the strategy trades
+    securities: HashMap<SecurityId, Security>,
+    portfolio: Addr<Portfolio>,
+    order_manager: Addr<OrderManager>,
+}
+
+impl StrategyActor {
+    pub fn new(
+        portfolio_code: String,
+        config: &StrategyConfig,
+        universe: &[Security],
+        portfolio: Addr<Portfolio>,
+        order_manager: Addr<OrderManager>,
+    ) -> Self {
+        let securities = universe
+            .iter()
+            .filter(|s| config.securities.is_empty() || config.securities.contains(&s.ticker))
+            .map(|s| (s.id, s.clone()))
+            .collect();
+
+        Self {
+            portfolio_code,
+            strategy: build_strategy(&config.kind, config.execution),
+            securities,
+            portfolio,
+            order_manager,
+        }
+    }
+
+    /// Orders and trades are sent one at a time, waiting for each reply before handling the next
+    /// message, so they reach the engine in the order they were signalled
+    fn act(&self, signals: Vec<Signal>, ctx: &mut Context<Self>) {
+        for signal in signals {
+            debug!("{} strategy signal, {:?}", self.portfolio_code, signal);
+            match signal {
+                Signal::Order {
+                    security_id,
+                    side,
+                    order_type,
+                    quantity,
+                } => {
+                    let order = NewOrder {
+                        portfolio_code: self.portfolio_code.clone(),
+                        security_id,
+                        side,
+                        order_type,
+                        quantity,
+                    };
+                    ctx.wait(
+                        self.order_manager
+                            .send(SubmitOrder(order))
+                            .into_actor(self)
+                            .map(|result, act, _| match result {
+                                Ok(Ok(_)) => {}
+                                Ok(Err(e)) => {
+                                    warn!("{} strategy order refused, {}", act.portfolio_code, e)
+                                }
+                                Err(e) => warn!("Failed to submit strategy order, {}", e),
+                            }),
+                    );
+                }
+                Signal::Trade(trade_type) => {
+                    let trade = Trade {
+                        portfolio_code: self.portfolio_code.clone(),
+                        trade_type,
+                    };
+                    ctx.wait(
+                        self.portfolio.send(trade).into_actor(self).map(
+                            |result, _, _| match result {
+                                Ok(Ok(())) => {}
+                                Ok(Err(rejection)) => {
+                                    info!("Strategy trade was not booked, {}", rejection)
+                                }
+                                Err(e) => warn!("Failed to send strategy trade, {}", e),
+                            },
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Actor for StrategyActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("Started strategy for {}", self.portfolio_code);
+    }
+}
+
+impl Handler<Tick> for StrategyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Tick, ctx: &mut Self::Context) -> Self::Result {
+        let Some(security) = self.securities.get(&msg.security_id) else {
+            return;
+        };
+
+        let signals = self.strategy.on_tick(security, &msg);
+        self.act(signals, ctx);
+    }
+}
+
+impl Handler<Flush> for StrategyActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _: &mut Self::Context) -> Self::Result {}
+}
+
+impl Handler<PortfolioStatsPublished> for StrategyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PortfolioStatsPublished, ctx: &mut Self::Context) -> Self::Result {
+        let signals = self.strategy.on_stats(&msg.0);
+        self.act(signals, ctx);
+    }
+}
+
+/// The last `lookback` prices of every security
+struct PriceHistory {
+    lookback: usize,
+    prices: HashMap<SecurityId, VecDeque<f64>>,
+}
+
+impl PriceHistory {
+    fn new(lookback: usize) -> Self {
+        Self {
+            lookback,
+            prices: HashMap::default(),
+        }
+    }
+
+    /// Record the tick, returning the full window once there is one
+    fn record(&mut self, tick: &Tick) -> Option<&VecDeque<f64>> {
+        let prices = self.prices.entry(tick.security_id).or_default();
+        prices.push_back(tick.price);
+        if prices.len() > self.lookback {
+            prices.pop_front();
+        }
+
+        (prices.len() == self.lookback).then_some(&*prices)
+    }
+}
+
+/// Quantity held per security according to the portfolio's stats, adjusted for the orders sent
+/// since, so a strategy does not buy or sell the same holding twice while its stats catch up
+struct Holdings {
+    execution: Execution,
+    held: HashMap<SecurityId, u32>,
+    /// Securities ordered since the previous stats cycle, which those stats may not reflect yet
+    ordered: HashSet<SecurityId>,
+}
+
+impl Holdings {
+    fn new(execution: Execution) -> Self {
+        Self {
+            execution,
+            held: HashMap::default(),
+            ordered: HashSet::default(),
+        }
+    }
+
+    fn held(&self, security_id: SecurityId) -> u32 {
+        self.held.get(&security_id).copied().unwrap_or(0)
+    }
+
+    fn buy(&mut self, security: &Security, quantity: u32, price: f64) -> Signal {
+        *self.held.entry(security.id).or_default() += quantity;
+        self.ordered.insert(security.id);
+
+        match self.execution {
+            Execution::Orders => market_order(security.id, Side::Buy, quantity),
+            Execution::Direct => Signal::Trade(TradeType::Open(Position {
+                id: next_position_id(),
+                security: security.clone(),
+                cost_basis: f64::from(quantity) * price,
+                size: quantity,
+                unrealized_pnl: 0f64,
+            })),
+        }
+    }
+
+    fn sell_all(&mut self, security: &Security, price: f64) -> Signal {
+        let quantity = self.held.remove(&security.id).unwrap_or(0);
+        self.ordered.insert(security.id);
+
+        match self.execution {
+            Execution::Orders => market_order(security.id, Side::Sell, quantity),
+            Execution::Direct => Signal::Trade(TradeType::Reduce {
+                security_id: security.id,
+                size: quantity,
+                price,
+            }),
+        }
+    }
+
+    fn update(&mut self, stats: &PortfolioStats) {
+        let mut held: HashMap<SecurityId, u32> = HashMap::default();
+        for p in &stats.positions {
+            *held.entry(p.security.id).or_default() += p.size;
+        }
+        for id in self.ordered.drain() {
+            match self.held.get(&id) {
+                Some(quantity) => held.insert(id, *quantity),
+                None => held.remove(&id),
+            };
+        }
+
+        self.held = held;
+    }
+}
+
+fn market_order(security_id: SecurityId, side: Side, quantity: u32) -> Signal {
+    Signal::Order {
+        security_id,
+        side,
+        order_type: OrderType::Market,
+        quantity,
+    }
+}
+
+struct Momentum {
+    prices: PriceHistory,
+    holdings: Holdings,
+    threshold: f64,
+    quantity: u32,
+}
+
+impl Strategy for Momentum {
+    fn on_tick(&mut self, security: &Security, tick: &Tick) -> Vec<Signal> {
+        let Some(prices) = self.prices.record(tick) else {
+            return vec![];
+        };
+        let change = tick.price / prices[0] - 1f64;
+        let held = self.holdings.held(tick.security_id);
+
+        if change > self.threshold && held == 0 {
+            vec![self.holdings.buy(security, self.quantity, tick.price)]
+        } else if change < -self.threshold && held > 0 {
+            vec![self.holdings.sell_all(security, tick.price)]
+        } else {
+            vec![]
+        }
+    }
+
+    fn on_stats(&mut self, stats: &PortfolioStats) -> Vec<Signal> {
+        self.holdings.update(stats);
+        vec![]
+    }
+}
+
+struct MeanReversion {
+    prices: PriceHistory,
+    holdings: Holdings,
+    threshold: f64,
+    quantity: u32,
+}
+
+impl Strategy for MeanReversion {
+    fn on_tick(&mut self, security: &Security, tick: &Tick) -> Vec<Signal> {
+        let Some(prices) = self.prices.record(tick) else {
+            return vec![];
+        };
+        let n = prices.len() as f64;
+        let mean = prices.iter().sum::<f64>() / n;
+        let std_dev = (prices.iter().map(|p| (p - mean).powi(2)).sum::<f64>() / n).sqrt();
+        if std_dev == 0f64 {
+            return vec![];
+        }
+        let z_score = (tick.price - mean) / std_dev;
+        let held = self.holdings.held(tick.security_id);
+
+        if z_score < -self.threshold && held == 0 {
+            vec![self.holdings.buy(security, self.quantity, tick.price)]
+        } else if z_score > 0f64 && held > 0 {
+            vec![self.holdings.sell_all(security, tick.price)]
+        } else {
+            vec![]
+        }
+    }
+
+    fn on_stats(&mut self, stats: &PortfolioStats) -> Vec<Signal> {
+        self.holdings.update(stats);
+        vec![]
+    }
+}
@@ -1,27 +1,56 @@
-use std::time::Duration;
+use std::{sync::RwLock, time::Duration};
 
 use crate::{
     models::*,
-    security_cache::SecurityCacheActor,
+    security_cache::{SecurityCache, SecurityCacheActor},
 };
 use actix::prelude::*;
-use log::{debug, error, info};
-use rand::{rngs::ThreadRng, Rng};
+use log::{debug, info};
+use rand::{distributions::Uniform, prelude::Distribution, rngs::ThreadRng};
+
+/// Largest relative move a security's price can make in a single tick
+const MAX_TICK_MOVE: f64 = 0.02;
 
 pub struct TickFeed {
     rng: ThreadRng,
     timescale: Duration,
+    security_cache: &'static RwLock<SecurityCache>,
     security_cache_actor: Addr<SecurityCacheActor>,
 }
 
 impl TickFeed {
-    pub fn new(security_cache_actor: Addr<SecurityCacheActor>, timescale: Duration) -> Self {
+    pub fn new(
+        security_cache_actor: Addr<SecurityCacheActor>,
+        security_cache: &'static RwLock<SecurityCache>,
+        timescale: Duration,
+    ) -> Self {
         Self {
             rng: rand::thread_rng(),
             timescale,
+            security_cache,
             security_cache_actor,
         }
     }
+
+    /// Random walk every security in the cache away from its last price
+    fn gen_ticks(&mut self) -> Vec<Tick> {
+        let cache = self
+            .security_cache
+            .read()
+            .expect("Failed to read security cache");
+        let moves = Uniform::new_inclusive(-MAX_TICK_MOVE, MAX_TICK_MOVE);
+
+        cache
+            .get_securities()
+            .into_iter()
+            .filter_map(|sec| {
+                cache.get_latest_price(sec.id).map(|price| Tick {
+                    security_id: sec.id,
+                    price: price * (1f64 + moves.sample(&mut self.rng)),
+                })
+            })
+            .collect()
+    }
 }
 
 impl Actor for TickFeed {
@@ -31,13 +60,11 @@ impl Actor for TickFeed {
         info!("Started TickFeed");
 
         ctx.run_interval(self.timescale, |act, _| {
-            debug!("Sending tick data for AAPL...");
-            if let Err(e) = act.security_cache_actor.try_send(Tick {
-                security_id: AAPL.id,
-                price: act.rng.gen_range(100.0f64..200f64),
-            }) {
-                error!("Failed to send, {:?}", e);
-            };
+            debug!("Sending tick data...");
+            // A full sweep of the universe is larger than the default mailbox capacity
+            for tick in act.gen_ticks() {
+                act.security_cache_actor.do_send(tick);
+            }
             debug!(" Complete!");
         });
     }
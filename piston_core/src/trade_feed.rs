@@ -10,7 +10,6 @@ pub struct TradeFeed {
     timescale: Duration,
     portfolios: HashMap<String, Addr<Portfolio>>,
     security_cache: &'static RwLock<SecurityCache>,
-    internal_next_trade_id: u32,
 }
 
 impl TradeFeed {
@@ -24,7 +23,6 @@ impl TradeFeed {
             timescale,
             portfolios,
             security_cache,
-            internal_next_trade_id: 0,
         }
     }
 
@@ -32,9 +30,9 @@ impl TradeFeed {
         let size = self.gen_size();
         let price = self.gen_price();
         Position {
-            id: self.next_trade_id(),
+            id: next_position_id(),
             security: self.gen_security(),
-            size: self.gen_size(),
+            size,
             cost_basis: price * f64::from(size),
             unrealized_pnl: 0f64,
         }
@@ -55,17 +53,10 @@ impl TradeFeed {
         Uniform::new(1, 500).sample(&mut self.rng)
     }
 
-    fn next_trade_id(&mut self) -> u32 {
-        let id = self.internal_next_trade_id;
-        self.internal_next_trade_id += 1;
-
-        id
-    }
-
     fn gen_duration(&mut self) -> Duration {
         let seconds = Uniform::new(1, 50).sample(&mut self.rng);
 
-        Duration::from_millis(seconds)
+        Duration::from_secs(seconds)
     }
 
     fn pick_random_portfolio(&mut self) -> (String, Addr<Portfolio>) {
@@ -77,6 +68,34 @@ impl TradeFeed {
         (key, self.portfolios[&cloned].clone())
     }
 
+    /// Close the position after a random delay
+    fn schedule_close(
+        &mut self,
+        ctx: &mut Context<Self>,
+        portfolio_code: String,
+        sub: Addr<Portfolio>,
+        position_id: PositionId,
+    ) {
+        let when_to_sell = self.gen_duration();
+        ctx.run_later(when_to_sell, move |_act, _ctx| {
+            let sell = Trade {
+                portfolio_code,
+                trade_type: TradeType::Close(position_id),
+            };
+
+            match sub.try_send(sell) {
+                Ok(_) => debug!(
+                    "Successfully scheduled position close for position ID {}",
+                    position_id
+                ),
+                Err(e) => error!(
+                    "Failed to schedule position close for position ID {}: {}",
+                    position_id, e
+                ),
+            };
+        });
+    }
+
     fn schedule_trade_generation(&mut self, ctx: &mut Context<Self>) {
         let batch_size = 5;
 
@@ -90,30 +109,17 @@ impl TradeFeed {
                     trade_type: TradeType::Open(position.clone()),
                 };
 
-                match sub.try_send(trade) {
-                    Ok(_) => debug!("Trade sent successfully"),
-                    Err(e) => error!("Failed to send trade: {}", e),
-                };
-
-                // Dynamically schedule the closing of the position
-                let when_to_sell = act.gen_duration();
-                ctx.run_later(when_to_sell, move |_act, _ctx| {
-                    let sell = Trade {
-                        portfolio_code: portfolio_code.clone(),
-                        trade_type: TradeType::Close(position.id),
-                    };
-
-                    match sub.try_send(sell) {
-                        Ok(_) => debug!(
-                            "Successfully scheduled position close for position ID {}",
-                            position.id
-                        ),
-                        Err(e) => error!(
-                            "Failed to schedule position close for position ID {}: {}",
-                            position.id, e
-                        ),
-                    };
-                });
+                // Only schedule the close once the portfolio has actually booked the position
+                ctx.spawn(sub.send(trade).into_actor(act).map(
+                    move |result, act, ctx| match result {
+                        Ok(Ok(())) => {
+                            debug!("Trade sent successfully");
+                            act.schedule_close(ctx, portfolio_code, sub, position.id);
+                        }
+                        Ok(Err(rejection)) => info!("Trade was not booked, {}", rejection),
+                        Err(e) => error!("Failed to send trade: {}", e),
+                    },
+                ));
             }
 
             // Reschedule the next trade generation
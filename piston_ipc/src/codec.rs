@@ -0,0 +1,56 @@
+use std::io::{Error, ErrorKind};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::messages::IpcMessage;
+
+/// Restricts the codecs this process offers, e.g. `json` to keep the wire human readable
+const CODEC_VAR: &str = "PISTON_IPC_CODEC";
+
+/// How messages are encoded on a connection. Both ends offer what they support in their `Hello`
+/// and the most compact codec they have in common is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Codec {
+    Json,
+    MessagePack,
+}
+
+lazy_static! {
+    static ref SUPPORTED_CODECS: Vec<Codec> = match std::env::var(CODEC_VAR).as_deref() {
+        Ok("json") => vec![Codec::Json],
+        Ok("msgpack") => vec![Codec::MessagePack],
+        _ => vec![Codec::Json, Codec::MessagePack],
+    };
+}
+
+/// Codecs this process offers, from `PISTON_IPC_CODEC` when set
+pub fn supported_codecs() -> Vec<Codec> {
+    SUPPORTED_CODECS.clone()
+}
+
+/// The most compact codec both ends support
+pub fn negotiate(ours: &[Codec], theirs: &[Codec]) -> Option<Codec> {
+    ours.iter().filter(|c| theirs.contains(c)).max().copied()
+}
+
+impl Codec {
+    pub fn encode(self, message: &IpcMessage) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(message)?),
+            // Field names are kept so the internally tagged `IpcMessage` can be decoded
+            Codec::MessagePack => {
+                rmp_serde::to_vec_named(message).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    pub fn decode(self, payload: &[u8]) -> std::io::Result<IpcMessage> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(payload)?),
+            Codec::MessagePack => {
+                rmp_serde::from_slice(payload).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{
+    messages::{IpcMessage, Request, Response, Subscription, Topic},
+    subscribe_to,
+    transport::Endpoint,
+    IpcStream,
+};
+
+/// Answer a message with `handler`. A `Request` is unwrapped and its answer wrapped in a
+/// `Response` with the same id
+pub(crate) fn answer(
+    message: IpcMessage,
+    handler: impl Fn(IpcMessage) -> IpcMessage,
+) -> IpcMessage {
+    match message {
+        IpcMessage::Request(Request { id, request }) => IpcMessage::Response(Response {
+            id,
+            response: Box::new(handler(*request)),
+        }),
+        other => handler(other),
+    }
+}
+
+/// Sends requests on the engine's message stream and waits for the response to each. The
+/// connection unsubscribes from every topic, so it carries little besides the responses
+pub struct IpcCommandClient {
+    stream: IpcStream,
+    next_id: u64,
+}
+
+impl IpcCommandClient {
+    /// Connect to the engine's message stream on the local socket. `peer` names this end of the
+    /// connection in the handshake
+    pub fn new(peer: &str) -> std::io::Result<Self> {
+        Self::connect(&Endpoint::default(), peer)
+    }
+
+    /// Connect to the engine's message stream on `endpoint`
+    pub fn connect(endpoint: &Endpoint, peer: &str) -> std::io::Result<Self> {
+        let mut stream = subscribe_to(endpoint, peer)?;
+        stream.send(&IpcMessage::Unsubscribe(Subscription {
+            topics: Topic::defaults(),
+        }))?;
+
+        Ok(Self { stream, next_id: 0 })
+    }
+
+    /// Send a request tagged with the next id and wait for the response to it
+    pub fn request(&mut self, request: IpcMessage) -> std::io::Result<IpcMessage> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stream.send(&IpcMessage::Request(Request {
+            id,
+            request: Box::new(request),
+        }))?;
+
+        loop {
+            let message = self.stream.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Engine hung up before responding",
+                )
+            })??;
+            match message {
+                IpcMessage::Response(r) if r.id == id => return Ok(*r.response),
+                // A request that could not be read is answered without an id
+                IpcMessage::Error(e) => return Ok(IpcMessage::Error(e)),
+                // The snapshot sent on attaching, and anything published before unsubscribing
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::Codec,
+        messages::{Ping, Pong},
+        protocol::write_message,
+        tests::engine_sending,
+    };
+
+    fn response(id: u64, response: IpcMessage) -> IpcMessage {
+        IpcMessage::Response(Response {
+            id,
+            response: Box::new(response),
+        })
+    }
+
+    #[test]
+    fn a_request_waits_for_its_own_response() {
+        let mut bytes = vec![];
+        write_message(
+            &mut bytes,
+            Codec::Json,
+            &response(7, IpcMessage::Ping(Ping)),
+        )
+        .unwrap();
+        write_message(
+            &mut bytes,
+            Codec::Json,
+            &response(0, IpcMessage::Pong(Pong)),
+        )
+        .unwrap();
+        let endpoint = engine_sending("command-client", bytes);
+        let mut client = IpcCommandClient::connect(&endpoint, "test").unwrap();
+
+        let answer = client.request(IpcMessage::Ping(Ping)).unwrap();
+        assert!(matches!(answer, IpcMessage::Pong(_)));
+    }
+}
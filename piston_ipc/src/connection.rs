@@ -0,0 +1,166 @@
+use log::{info, warn};
+use std::{
+    io::{Error, ErrorKind},
+    thread,
+    time::Duration,
+};
+
+use crate::{messages::IpcMessage, subscribe_to, transport::Endpoint, IpcStream};
+
+/// Wait before the first retry, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Where a `Connection` is with the engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Trying to connect, after `attempts` failed tries
+    Connecting {
+        attempts: u32,
+    },
+    Connected,
+    /// The engine hung up or stopped sending heartbeats. The next read reconnects
+    Disconnected {
+        reason: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum ConnectionEvent {
+    /// A new connection is up and the setup requests have been sent on it. Anything built from
+    /// an earlier connection, such as portfolios rebuilt from deltas, starts over from the
+    /// snapshots sent on this one
+    Connected,
+    Disconnected(String),
+    Message(Box<IpcMessage>),
+}
+
+/// A subscription to the engine's stream that reconnects, with backoff, whenever the engine goes
+/// away. The `setup` requests, such as `Subscribe`, are sent again on every new connection
+pub struct Connection {
+    endpoint: Endpoint,
+    peer: String,
+    setup: Vec<IpcMessage>,
+    stream: Option<IpcStream>,
+    state: ConnectionState,
+}
+
+impl Connection {
+    /// A connection to the engine on a local or TCP `endpoint`
+    pub fn new(endpoint: Endpoint, peer: &str, setup: Vec<IpcMessage>) -> Self {
+        Self {
+            endpoint,
+            peer: peer.to_string(),
+            setup,
+            stream: None,
+            state: ConnectionState::Connecting { attempts: 0 },
+        }
+    }
+
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    /// Send a request to the engine. Its response arrives as a `ConnectionEvent::Message`
+    pub fn send(&mut self, message: &IpcMessage) -> std::io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => stream.send(message),
+            None => Err(Error::new(
+                ErrorKind::NotConnected,
+                "Not connected to the engine",
+            )),
+        }
+    }
+
+    /// Connect, retrying until it works
+    fn connect(&mut self) {
+        let mut attempts = 0;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            self.state = ConnectionState::Connecting { attempts };
+            let connected = subscribe_to(&self.endpoint, &self.peer).and_then(|mut stream| {
+                for request in &self.setup {
+                    stream.send(request)?;
+                }
+                Ok(stream)
+            });
+
+            match connected {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.state = ConnectionState::Connected;
+                    return;
+                }
+                Err(e) => {
+                    attempts += 1;
+                    info!(
+                        "Failed to connect to the engine on {}, retrying in {:?}, {}",
+                        self.endpoint, backoff, e
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// Events on the connection, forever. Blocks while reconnecting
+impl Iterator for Connection {
+    type Item = ConnectionEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(stream) = &mut self.stream else {
+            self.connect();
+            return Some(ConnectionEvent::Connected);
+        };
+
+        loop {
+            let reason = match stream.next() {
+                Some(Ok(message)) => return Some(ConnectionEvent::Message(Box::new(message))),
+                Some(Err(e)) if e.kind() == ErrorKind::InvalidData => {
+                    warn!("Failed to read message, {}", e);
+                    continue;
+                }
+                Some(Err(e)) => e.to_string(),
+                None => String::from("The engine hung up"),
+            };
+
+            self.stream = None;
+            self.state = ConnectionState::Disconnected {
+                reason: reason.clone(),
+            };
+            return Some(ConnectionEvent::Disconnected(reason));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{bad_length, engine_sending};
+
+    #[test]
+    fn a_bad_frame_length_disconnects() {
+        let endpoint = engine_sending("bad-length-connection", bad_length());
+        let mut connection = Connection::new(endpoint, "test", vec![]);
+
+        assert!(matches!(
+            connection.next(),
+            Some(ConnectionEvent::Connected)
+        ));
+        assert!(matches!(
+            connection.next(),
+            Some(ConnectionEvent::Message(m)) if matches!(*m, IpcMessage::Pong(_))
+        ));
+        assert!(matches!(
+            connection.next(),
+            Some(ConnectionEvent::Disconnected(reason)) if reason.contains("bad frame length")
+        ));
+        assert!(matches!(
+            connection.state(),
+            ConnectionState::Disconnected { .. }
+        ));
+    }
+}
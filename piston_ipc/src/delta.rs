@@ -0,0 +1,286 @@
+use piston_shared::*;
+use std::collections::HashMap;
+
+use crate::messages::{PortfolioChange, PortfolioDelta, PortfolioSnapshot, PortfolioSummary};
+
+impl PortfolioSummary {
+    pub fn of(stats: &PortfolioStats) -> Self {
+        Self {
+            trade_count: stats.trade_count,
+            rejected_trade_count: stats.rejected_trade_count,
+            trading_state: stats.trading_state,
+            pnl: stats.pnl,
+            unrealized_pnl: stats.unrealized_pnl,
+            attribution: stats.attribution.clone(),
+            cash: stats.cash.clone(),
+            nav: stats.nav,
+            margin: stats.margin.clone(),
+            exposure: stats.exposure.clone(),
+        }
+    }
+
+    fn apply(self, stats: &mut PortfolioStats) {
+        stats.trade_count = self.trade_count;
+        stats.rejected_trade_count = self.rejected_trade_count;
+        stats.trading_state = self.trading_state;
+        stats.pnl = self.pnl;
+        stats.unrealized_pnl = self.unrealized_pnl;
+        stats.attribution = self.attribution;
+        stats.cash = self.cash;
+        stats.nav = self.nav;
+        stats.margin = self.margin;
+        stats.exposure = self.exposure;
+    }
+}
+
+/// Changes that turn `previous` into `current`, or all of `current` when nothing was published
+/// before it
+pub fn changes(
+    previous: Option<&PortfolioStats>,
+    current: &PortfolioStats,
+) -> Vec<PortfolioChange> {
+    let before: HashMap<_, _> = previous
+        .into_iter()
+        .flat_map(|stats| &stats.positions)
+        .map(|p| (p.id, p))
+        .collect();
+
+    let mut changes = vec![];
+    for p in &current.positions {
+        match before.get(&p.id) {
+            None => changes.push(PortfolioChange::PositionOpened(p.clone())),
+            Some(previous) if *previous != p => {
+                changes.push(PortfolioChange::PositionUpdated(p.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    for id in before.keys() {
+        if !current.positions.iter().any(|p| p.id == *id) {
+            changes.push(PortfolioChange::PositionClosed(*id));
+        }
+    }
+
+    let summary = PortfolioSummary::of(current);
+    if previous.map(PortfolioSummary::of).as_ref() != Some(&summary) {
+        changes.push(PortfolioChange::StatsChanged(Box::new(summary)));
+    }
+
+    changes
+}
+
+/// Apply the changes in a delta to the stats it follows
+pub fn apply(stats: &mut PortfolioStats, changes: Vec<PortfolioChange>) {
+    for change in changes {
+        match change {
+            PortfolioChange::PositionOpened(p) | PortfolioChange::PositionUpdated(p) => match stats
+                .positions
+                .iter_mut()
+                .find(|existing| existing.id == p.id)
+            {
+                Some(existing) => *existing = p,
+                None => stats.positions.push(p),
+            },
+            PortfolioChange::PositionClosed(id) => stats.positions.retain(|p| p.id != id),
+            PortfolioChange::StatsChanged(summary) => summary.apply(stats),
+        }
+    }
+}
+
+/// Portfolio stats kept up to date on the client from snapshots and the deltas after them
+#[derive(Debug, Default)]
+pub struct PortfolioReplica {
+    portfolios: HashMap<String, PortfolioSnapshot>,
+}
+
+impl PortfolioReplica {
+    pub fn snapshot(&mut self, snapshot: PortfolioSnapshot) -> &PortfolioStats {
+        let code = snapshot.stats.code.clone();
+        &self
+            .portfolios
+            .entry(code)
+            .insert_entry(snapshot)
+            .into_mut()
+            .stats
+    }
+
+    /// Apply a delta, returning the updated stats, or `None` for a delta the latest snapshot
+    /// already includes or for a portfolio without a snapshot. When a delta has been missed the
+    /// portfolio is dropped and its code returned as the error, to be resynced
+    pub fn apply(&mut self, delta: PortfolioDelta) -> Result<Option<&PortfolioStats>, String> {
+        let Some(sequence) = self.portfolios.get(&delta.code).map(|s| s.sequence) else {
+            return Ok(None);
+        };
+        if delta.sequence <= sequence {
+            return Ok(None);
+        }
+        if delta.sequence != sequence + 1 {
+            self.portfolios.remove(&delta.code);
+            return Err(delta.code);
+        }
+
+        let snapshot = self
+            .portfolios
+            .get_mut(&delta.code)
+            .expect("Snapshot was just found");
+        snapshot.sequence = delta.sequence;
+        apply(&mut snapshot.stats, delta.changes);
+        Ok(Some(&snapshot.stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(id: PositionId, size: u32, unrealized_pnl: f64) -> Position {
+        Position {
+            id,
+            security: Security::new(id, format!("SEC{}", id)),
+            cost_basis: 100f64 * f64::from(size),
+            size,
+            unrealized_pnl,
+        }
+    }
+
+    fn stats(positions: Vec<Position>, pnl: f64) -> PortfolioStats {
+        PortfolioStats {
+            code: String::from("TEST"),
+            positions,
+            trade_count: 0,
+            rejected_trade_count: 0,
+            trading_state: TradingState::Active,
+            pnl,
+            unrealized_pnl: 0f64,
+            attribution: PnlAttribution::default(),
+            cash: vec![],
+            nav: 0f64,
+            margin: PortfolioMargin {
+                initial_requirement: 0f64,
+                maintenance_requirement: 0f64,
+                initial_excess: 0f64,
+                maintenance_excess: 0f64,
+                leverage: 0f64,
+                state: MarginState::Healthy,
+            },
+            exposure: PortfolioExposure {
+                gross: 0f64,
+                net: 0f64,
+                long: 0f64,
+                short: 0f64,
+                largest: None,
+                by_group: vec![],
+            },
+        }
+    }
+
+    fn assert_same(a: &PortfolioStats, b: &PortfolioStats) {
+        let sorted = |stats: &PortfolioStats| {
+            let mut positions = stats.positions.clone();
+            positions.sort_by_key(|p| p.id);
+            positions
+        };
+        assert_eq!(sorted(a), sorted(b));
+        assert_eq!(PortfolioSummary::of(a), PortfolioSummary::of(b));
+    }
+
+    fn delta(sequence: u64, changes: Vec<PortfolioChange>) -> PortfolioDelta {
+        PortfolioDelta {
+            code: String::from("TEST"),
+            sequence,
+            changes,
+        }
+    }
+
+    #[test]
+    fn changes_applied_to_the_previous_stats_give_the_current() {
+        let previous = stats(vec![position(1, 10, 0f64), position(2, 20, 5f64)], 0f64);
+        let current = stats(vec![position(2, 20, 7f64), position(3, 30, 0f64)], 12f64);
+
+        let delta = changes(Some(&previous), &current);
+        assert_eq!(delta.len(), 4);
+        let mut replayed = previous.clone();
+        apply(&mut replayed, delta);
+        assert_same(&replayed, &current);
+    }
+
+    #[test]
+    fn nothing_changes_between_identical_stats() {
+        let current = stats(vec![position(1, 10, 0f64)], 3f64);
+        assert!(changes(Some(&current), &current).is_empty());
+    }
+
+    #[test]
+    fn the_first_changes_carry_everything() {
+        let current = stats(vec![position(1, 10, 0f64), position(2, 20, 0f64)], 3f64);
+
+        let delta = changes(None, &current);
+        assert!(matches!(
+            delta.last(),
+            Some(PortfolioChange::StatsChanged(_))
+        ));
+        let mut replayed = stats(vec![], 0f64);
+        apply(&mut replayed, delta);
+        assert_same(&replayed, &current);
+    }
+
+    #[test]
+    fn replica_applies_deltas_in_sequence() {
+        let mut replica = PortfolioReplica::default();
+        let initial = stats(vec![position(1, 10, 0f64)], 0f64);
+        replica.snapshot(PortfolioSnapshot {
+            sequence: 4,
+            stats: initial.clone(),
+        });
+
+        let next = stats(vec![position(1, 10, 2f64)], 1f64);
+        let applied = replica
+            .apply(delta(5, changes(Some(&initial), &next)))
+            .unwrap()
+            .unwrap();
+        assert_same(applied, &next);
+    }
+
+    #[test]
+    fn replica_skips_deltas_it_already_has() {
+        let mut replica = PortfolioReplica::default();
+        let initial = stats(vec![position(1, 10, 0f64)], 0f64);
+        replica.snapshot(PortfolioSnapshot {
+            sequence: 4,
+            stats: initial.clone(),
+        });
+
+        let stale = changes(None, &stats(vec![], 9f64));
+        assert!(replica.apply(delta(4, stale.clone())).unwrap().is_none());
+        assert!(replica.apply(delta(3, stale)).unwrap().is_none());
+
+        // Still at sequence 4, so the next delta applies to the untouched snapshot
+        let applied = replica.apply(delta(5, vec![])).unwrap().unwrap();
+        assert_same(applied, &initial);
+    }
+
+    #[test]
+    fn replica_drops_a_portfolio_after_a_gap() {
+        let mut replica = PortfolioReplica::default();
+        replica.snapshot(PortfolioSnapshot {
+            sequence: 4,
+            stats: stats(vec![], 0f64),
+        });
+
+        assert_eq!(replica.apply(delta(6, vec![])).unwrap_err(), "TEST");
+        // Nothing is applied until it is resynced
+        assert!(replica.apply(delta(7, vec![])).unwrap().is_none());
+
+        replica.snapshot(PortfolioSnapshot {
+            sequence: 7,
+            stats: stats(vec![], 0f64),
+        });
+        assert!(replica.apply(delta(8, vec![])).unwrap().is_some());
+    }
+
+    #[test]
+    fn replica_ignores_portfolios_without_a_snapshot() {
+        let mut replica = PortfolioReplica::default();
+        assert!(replica.apply(delta(1, vec![])).unwrap().is_none());
+    }
+}
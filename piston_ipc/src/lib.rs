@@ -1,105 +1,598 @@
+use codec::Codec;
+use commands::answer;
 use lazy_static::lazy_static;
-use log::{error, info};
-use messages::IpcMessage;
+use log::{error, info, trace, warn};
+use messages::{
+    IpcError, IpcMessage, IpcMetrics, Ping, Pong, Resync, SubscriberMetrics, Subscription, Topic,
+};
+use outbox::{Outbox, Outgoing, PublisherConfig, Pushed};
+use protocol::{handshake, read_message, write_message, FrameWrite};
 use std::{
-    io::{BufReader, Write},
-    path::Path,
-    sync::RwLock,
-    time::Duration,
+    collections::{HashMap, HashSet},
+    io::{Error, ErrorKind},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use interprocess::local_socket::{LocalSocketListener, LocalSocketStream};
-use serde::Serialize;
-use serde_json::{de::IoRead, Deserializer, StreamDeserializer};
-
+pub mod codec;
+pub mod commands;
+pub mod connection;
+pub mod delta;
 pub mod messages;
+pub mod outbox;
+pub mod protocol;
+pub mod transport;
+mod websocket;
+
+use transport::{Channel, Endpoint, Listener, Reader, Socket, Writer};
+
+/// How often the engine pings its subscribers
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Either end gives up on the other after hearing nothing from it for this long
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A client attached to the engine's message stream
+struct Subscriber {
+    id: u64,
+    peer: String,
+    /// Only used to hang up, the subscriber's writer thread has its own handle
+    socket: Socket,
+    codec: Codec,
+    topics: HashSet<Topic>,
+    /// When the subscriber last sent anything, its `Pong`s included
+    last_heard: Instant,
+    outbox: Arc<Outbox>,
+    /// Dropped messages already logged
+    dropped_reported: u64,
+}
 
-pub struct IpcReader {
-    listener: LocalSocketListener,
+impl Subscriber {
+    fn subscriptions(&self) -> IpcMessage {
+        let mut topics: Vec<_> = self.topics.iter().cloned().collect();
+        topics.sort();
+        IpcMessage::Subscriptions(Subscription { topics })
+    }
+
+    /// Hang up on the subscriber, which also ends its reader and writer threads
+    fn detach(&self) {
+        self.outbox.close();
+        let _ = self.socket.shutdown();
+    }
+
+    fn metrics(&self) -> SubscriberMetrics {
+        let counts = self.outbox.counts();
+        SubscriberMetrics {
+            id: self.id,
+            peer: self.peer.clone(),
+            codec: self.codec,
+            queued: counts.queued,
+            sent: counts.sent,
+            dropped: counts.dropped,
+            conflated: counts.conflated,
+        }
+    }
 }
 
-const SOCKET_PATH: &str = "/tmp/piston-ipc.sock";
+static NEXT_SUBSCRIBER_ID: AtomicU64 = AtomicU64::new(0);
+
+/* Totals across every subscriber, including those that have since detached */
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static CONFLATED: AtomicU64 = AtomicU64::new(0);
+static SLOW_CONSUMERS_DISCONNECTED: AtomicU64 = AtomicU64::new(0);
 
-/* Every single writer writes to the same connection - not multiple connections at once */
+/* Every writer broadcasts to the same set of subscribers */
 lazy_static! {
-    static ref SOCKET_WRITER_CONNECTION: RwLock<LocalSocketStream> = {
-        let path = Path::new(SOCKET_PATH);
+    static ref SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(vec![]);
+}
 
-        loop {
-            if let Ok(connection) = LocalSocketStream::connect(path) {
-                info!("Connected to {}", path.display());
-                return RwLock::new(connection);
+/// The engine's end of the message stream. Any number of clients can attach to each of the
+/// configured endpoints, and every message sent by an `IpcWriter` is queued for those subscribed
+/// to its topic, whichever transport they came in on. Each subscriber's queue is written by a
+/// thread of its own, so a slow reader only holds itself up
+pub struct IpcPublisher {
+    listeners: Vec<(Endpoint, Listener)>,
+    config: PublisherConfig,
+}
+
+impl IpcPublisher {
+    pub fn bind(config: PublisherConfig) -> std::io::Result<Self> {
+        let listeners = config
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                let listener = Listener::bind(endpoint).map_err(|e| {
+                    Error::new(e.kind(), format!("Failed to bind {}, {}", endpoint, e))
+                })?;
+                info!("Serving the message stream on {}", endpoint);
+                Ok((endpoint.clone(), listener))
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self { listeners, config })
+    }
+
+    /// Accept subscribers on a background thread until the process exits. Requests other than
+    /// `Subscribe` and `Unsubscribe` are answered with `handler`. Subscribers are pinged every
+    /// `HEARTBEAT_INTERVAL` and detached once they have been silent for `HEARTBEAT_TIMEOUT`
+    pub fn serve<F>(self, handler: F)
+    where
+        F: Fn(IpcMessage) -> IpcMessage + Clone + Send + 'static,
+    {
+        thread::spawn(heartbeat);
+        for (endpoint, listener) in self.listeners {
+            let handler = handler.clone();
+            let config = self.config.clone();
+            thread::spawn(move || loop {
+                match listener.accept() {
+                    // Each subscriber gets a thread to read its requests on
+                    Ok(socket) => {
+                        let handler = handler.clone();
+                        let endpoint = endpoint.clone();
+                        let outbox = Arc::new(Outbox::new(&config));
+                        thread::spawn(move || attach(socket, &endpoint, outbox, handler));
+                    }
+                    Err(e) => error!("Failed to accept subscriber on {}, {:?}", endpoint, e),
+                }
+            });
+        }
+    }
+}
+
+fn heartbeat() {
+    loop {
+        thread::sleep(HEARTBEAT_INTERVAL);
+        IpcWriter::new().send(&IpcMessage::Ping(Ping));
+
+        let mut subscribers = SUBSCRIBERS.lock().expect("Failed to lock subscribers");
+        subscribers.retain_mut(|s| {
+            let dropped = s.outbox.counts().dropped;
+            if dropped > s.dropped_reported {
+                warn!(
+                    "{} is reading too slowly, dropped {} messages",
+                    s.peer,
+                    dropped - s.dropped_reported
+                );
+                s.dropped_reported = dropped;
             }
 
-            // TODO feels weird to log in a library?
-            error!("Failed to connect to socket {}, retrying...", path.display());
-            std::thread::sleep(Duration::from_secs(1));
+            if s.last_heard.elapsed() < HEARTBEAT_TIMEOUT {
+                return true;
+            }
+            warn!("{} stopped answering heartbeats, detaching it", s.peer);
+            s.detach();
+            false
+        });
+    }
+}
+
+fn attach(
+    socket: Socket,
+    endpoint: &Endpoint,
+    outbox: Arc<Outbox>,
+    handler: impl Fn(IpcMessage) -> IpcMessage,
+) {
+    let address = socket.peer_address();
+    // A client that connects and says nothing must not hold the thread forever
+    let accepted = socket
+        .set_read_timeout(Some(HEARTBEAT_TIMEOUT))
+        .and_then(|()| Channel::accept(socket, endpoint))
+        .and_then(|mut channel| {
+            let (hello, codec) = handshake(&mut channel, "piston_core")?;
+            // From here on silence is dealt with by the heartbeat
+            channel.socket.set_read_timeout(None)?;
+            Ok((hello, codec, channel))
+        });
+    let (hello, codec, channel) = match accepted {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Subscriber handshake on {} failed, {}", endpoint, e);
+            return;
         }
     };
+    let Channel {
+        reader,
+        writer,
+        socket,
+    } = channel;
+    let hangup = match socket.try_clone() {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to write to subscriber {}, {}", hello.peer, e);
+            return;
+        }
+    };
+
+    let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+    info!(
+        "{} subscribed on {} from {}, sending {:?}",
+        hello.peer, endpoint, address, codec
+    );
+    SUBSCRIBERS
+        .lock()
+        .expect("Failed to lock subscribers")
+        .push(Subscriber {
+            id,
+            peer: hello.peer,
+            socket,
+            codec,
+            topics: Topic::defaults().into_iter().collect(),
+            last_heard: Instant::now(),
+            outbox: outbox.clone(),
+            dropped_reported: 0,
+        });
+    thread::spawn(move || drain(outbox, writer, hangup));
+
+    // Portfolios are streamed as deltas, so start the subscriber off with a snapshot of them
+    if !reply(id, &handler(IpcMessage::Resync(Resync::default()))) {
+        return;
+    }
+
+    listen(id, reader, handler);
 }
 
-impl IpcReader {
-    pub fn new() -> std::io::Result<Self> {
-        let path = Path::new(SOCKET_PATH);
+/// Answer a subscriber's requests until it hangs up, then detach it. Responses are interleaved
+/// with the messages it is subscribed to
+fn listen(id: u64, mut reader: Reader, handler: impl Fn(IpcMessage) -> IpcMessage) {
+    loop {
+        let request = read_message(&mut reader);
+        let resync = match &request {
+            Ok(Some(request)) => resync_for(request),
+            _ => None,
+        };
+        if matches!(request, Ok(Some(_))) {
+            heard_from(id);
+        }
+        let response = match request {
+            // Answers one of our heartbeats, so needs no response
+            Ok(Some(IpcMessage::Pong(_))) => continue,
+            Ok(Some(request)) => answer(request, |request| match request {
+                IpcMessage::Subscribe(s) => update_topics(id, |topics| topics.extend(s.topics)),
+                IpcMessage::Unsubscribe(s) => update_topics(id, |topics| {
+                    for topic in &s.topics {
+                        topics.remove(topic);
+                    }
+                }),
+                other => handler(other),
+            }),
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                error_message(format!("Failed to read request, {}", e))
+            }
+            Err(_) => break,
+        };
 
-        if path.exists() {
-            std::fs::remove_file(path)?;
+        if !reply(id, &response) {
+            break;
+        }
+        if let Some(resync) = resync {
+            if !reply(id, &handler(IpcMessage::Resync(resync))) {
+                break;
+            }
         }
+    }
 
-        Ok(Self {
-            listener: LocalSocketListener::bind(path.to_path_buf())?,
-        })
+    let mut subscribers = SUBSCRIBERS.lock().expect("Failed to lock subscribers");
+    if let Some(i) = subscribers.iter().position(|s| s.id == id) {
+        let subscriber = subscribers.remove(i);
+        subscriber.detach();
+        info!("{} unsubscribed", subscriber.peer);
     }
+}
 
-    pub fn as_stream(
-        &self,
-    ) -> std::io::Result<StreamDeserializer<IoRead<BufReader<LocalSocketStream>>, IpcMessage>> {
-        let stream = self.listener.accept()?;
-        let reader = BufReader::new(stream);
-        let deserializer = Deserializer::from_reader(reader).into_iter::<IpcMessage>();
+/// Write a subscriber's queued messages until it is detached or can no longer be written to
+fn drain(outbox: Arc<Outbox>, mut writer: Writer, socket: Socket) {
+    while let Some(message) = outbox.pop() {
+        if let Err(e) = writer.write_frame(message.kind, &message.payload) {
+            info!("Failed to write to subscriber, {}", e);
+            // The reader thread sees the hang up and detaches the subscriber
+            outbox.close();
+            let _ = socket.shutdown();
+            return;
+        }
+        outbox.sent();
+    }
+}
 
-        Ok(deserializer)
+fn heard_from(id: u64) {
+    let mut subscribers = SUBSCRIBERS.lock().expect("Failed to lock subscribers");
+    if let Some(subscriber) = subscribers.iter_mut().find(|s| s.id == id) {
+        subscriber.last_heard = Instant::now();
+    }
+}
 
-        // for message in deserializer {
-        //     match message {
-        //         Ok(msg) => println!("{:?}", msg),
-        //         Err(e) => println!("Failed to deserialize, {:?}", e),
-        //     }
-        // }
+/// The portfolios to send snapshots of after a `Subscribe` adds them, if any
+fn resync_for(request: &IpcMessage) -> Option<Resync> {
+    match request {
+        IpcMessage::Request(r) => resync_for(&r.request),
+        IpcMessage::Subscribe(s) if s.topics.contains(&Topic::Portfolios) => {
+            Some(Resync::default())
+        }
+        IpcMessage::Subscribe(s) => {
+            let portfolio_codes: Vec<_> = s
+                .topics
+                .iter()
+                .filter_map(|topic| match topic {
+                    Topic::Portfolio(code) => Some(code.clone()),
+                    _ => None,
+                })
+                .collect();
+            (!portfolio_codes.is_empty()).then_some(Resync { portfolio_codes })
+        }
+        _ => None,
     }
 }
 
-impl Drop for IpcReader {
-    fn drop(&mut self) {
-        let _ = std::fs::remove_file(SOCKET_PATH);
+/// Change a subscriber's topics, returning the ones it now has
+fn update_topics(id: u64, update: impl FnOnce(&mut HashSet<Topic>)) -> IpcMessage {
+    let mut subscribers = SUBSCRIBERS.lock().expect("Failed to lock subscribers");
+    match subscribers.iter_mut().find(|s| s.id == id) {
+        Some(subscriber) => {
+            update(&mut subscriber.topics);
+            subscriber.subscriptions()
+        }
+        None => error_message(String::from("Subscriber has detached")),
     }
 }
 
-#[derive(Debug)]
-pub struct IpcWriter {
-    connection: &'static RwLock<LocalSocketStream>,
+/// Queue a response for a single subscriber. Returns whether the subscriber is still attached
+fn reply(id: u64, message: &IpcMessage) -> bool {
+    let subscribers = SUBSCRIBERS.lock().expect("Failed to lock subscribers");
+    let Some(subscriber) = subscribers.iter().find(|s| s.id == id) else {
+        return false;
+    };
+
+    match subscriber.codec.encode(message) {
+        Ok(payload) => {
+            let pushed = subscriber.outbox.respond(Outgoing {
+                kind: subscriber.codec.into(),
+                payload: Arc::new(payload),
+                key: None,
+            });
+            match pushed {
+                Pushed::Full => {
+                    // The reader thread detaches it on the way out
+                    warn!(
+                        "{} is not reading its responses, detaching it",
+                        subscriber.peer
+                    );
+                    SLOW_CONSUMERS_DISCONNECTED.fetch_add(1, Ordering::Relaxed);
+                    false
+                }
+                Pushed::Closed => false,
+                _ => true,
+            }
+        }
+        Err(e) => {
+            error!("Failed to encode response {:?}, {}", message, e);
+            true
+        }
+    }
+}
+
+/// Queue lengths and messages sent and lost per subscriber
+pub fn metrics() -> IpcMetrics {
+    let subscribers = SUBSCRIBERS.lock().expect("Failed to lock subscribers");
+    IpcMetrics {
+        subscribers: subscribers.iter().map(Subscriber::metrics).collect(),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        conflated: CONFLATED.load(Ordering::Relaxed),
+        slow_consumers_disconnected: SLOW_CONSUMERS_DISCONNECTED.load(Ordering::Relaxed),
+    }
+}
+
+fn error_message(message: String) -> IpcMessage {
+    IpcMessage::Error(IpcError { message })
+}
+
+/// Attach to the engine's message stream on the local socket with the default topics. `peer`
+/// names this end of the connection in the handshake. See `connection::Connection` for a
+/// subscription that survives the engine restarting
+pub fn subscribe(peer: &str) -> std::io::Result<IpcStream> {
+    subscribe_to(&Endpoint::default(), peer)
+}
+
+/// Attach to the engine's message stream on a local or TCP endpoint
+pub fn subscribe_to(endpoint: &Endpoint, peer: &str) -> std::io::Result<IpcStream> {
+    let mut channel = Channel::connect(endpoint)?;
+    let (hello, codec) = handshake(&mut channel, peer)?;
+    // The engine pings regularly, so a read that times out means it has gone away
+    channel.socket.set_read_timeout(Some(HEARTBEAT_TIMEOUT))?;
+    info!("Subscribed to {} on {}", hello.peer, endpoint);
+
+    Ok(IpcStream {
+        reader: channel.reader,
+        writer: channel.writer,
+        codec,
+        closed: false,
+    })
+}
+
+/// Messages read off a connection until the other end hangs up or stops sending heartbeats. The
+/// engine's `Ping`s are answered here rather than yielded. A message that fails to decode is
+/// yielded as an error and the stream carries on with the next one, while any other error ends
+/// the stream
+pub struct IpcStream {
+    reader: Reader,
+    writer: Writer,
+    codec: Codec,
+    closed: bool,
+}
+
+impl IpcStream {
+    /// Send a request, such as `Subscribe`, to the engine. Its response arrives on the stream
+    pub fn send(&mut self, message: &IpcMessage) -> std::io::Result<()> {
+        write_message(&mut self.writer, self.codec, message)
+    }
+}
+
+impl Iterator for IpcStream {
+    type Item = std::io::Result<IpcMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.closed {
+            return None;
+        }
+
+        loop {
+            let message = match read_message(&mut self.reader) {
+                Ok(Some(IpcMessage::Ping(_))) => match self.send(&IpcMessage::Pong(Pong)) {
+                    Ok(()) => continue,
+                    Err(e) => Some(Err(e)),
+                },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    Some(Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("No heartbeat from the engine in {:?}", HEARTBEAT_TIMEOUT),
+                    )))
+                }
+                other => other.transpose(),
+            };
+
+            if let Some(Err(e)) = &message {
+                self.closed = e.kind() != ErrorKind::InvalidData;
+            }
+            return message;
+        }
+    }
 }
 
+/// Broadcasts messages to every subscriber attached to the `IpcPublisher` whose topics include
+/// them. Messages are dropped when nobody is subscribed, including when the publisher was never
+/// started. Sending never fails: subscribers that cannot be written to are detached, and a
+/// message that cannot be encoded is logged and dropped
+#[derive(Debug, Default)]
+pub struct IpcWriter;
+
 impl IpcWriter {
-    pub fn new() -> std::io::Result<Self> {
-        Ok(Self {
-            connection: &SOCKET_WRITER_CONNECTION,
-        })
-    }
-
-    pub fn send<T: Serialize>(&mut self, message: &T) -> std::io::Result<()> {
-        let message = serde_json::to_string(message)?;
-        println!("Sending: {}", message);
-        self.connection
-            .write()
-            .expect("Failed to get writer")
-            .write_all(message.as_bytes())?;
-        self.connection
-            .write()
-            .expect("Failed to get writer")
-            .flush()
-            .expect("Failed to flush");
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn send(&mut self, message: &IpcMessage) {
+        if let Err(e) = self.broadcast(message) {
+            error!("Failed to send {:?}, {}", message, e);
+        }
+    }
+
+    fn broadcast(&mut self, message: &IpcMessage) -> std::io::Result<()> {
+        trace!("Sending {:?}", message);
+        let mut subscribers = SUBSCRIBERS.lock().expect("Failed to lock subscribers");
+        let recipients: HashSet<_> = subscribers
+            .iter()
+            .filter(|s| message.is_subscribed(&s.topics))
+            .map(|s| s.id)
+            .collect();
+        if recipients.is_empty() {
+            return Ok(());
+        }
+
+        // Encode once per codec in use rather than once per subscriber
+        let codecs: HashSet<_> = subscribers
+            .iter()
+            .filter(|s| recipients.contains(&s.id))
+            .map(|s| s.codec)
+            .collect();
+        let payloads = codecs
+            .into_iter()
+            .map(|codec| Ok((codec, Arc::new(codec.encode(message)?))))
+            .collect::<std::io::Result<HashMap<_, _>>>()?;
+        let key = message.conflation_key();
+
+        // Only queued here, each subscriber's writer thread does the writing
+        subscribers.retain(|s| {
+            if !recipients.contains(&s.id) {
+                return true;
+            }
+            let pushed = s.outbox.push(Outgoing {
+                kind: s.codec.into(),
+                payload: payloads[&s.codec].clone(),
+                key: key.clone(),
+            });
+            match pushed {
+                Pushed::Queued => true,
+                Pushed::Dropped => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Pushed::Conflated => {
+                    CONFLATED.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Pushed::Full => {
+                    warn!("{} is reading too slowly, detaching it", s.peer);
+                    SLOW_CONSUMERS_DISCONNECTED.fetch_add(1, Ordering::Relaxed);
+                    s.detach();
+                    false
+                }
+                // Its writer failed, and the subscriber is on its way out
+                Pushed::Closed => false,
+            }
+        });
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use protocol::FrameKind;
+    use std::{io::Write, os::unix::net::UnixListener};
+
+    /// An engine on a fresh local socket that answers one handshake, sends a `Pong` and then
+    /// `bytes`, and hangs up once the client has
+    pub(crate) fn engine_sending(name: &str, bytes: Vec<u8>) -> Endpoint {
+        let path =
+            std::env::temp_dir().join(format!("piston-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let (_, codec) = handshake(&mut socket, "engine").unwrap();
+            write_message(&mut socket, codec, &IpcMessage::Pong(Pong)).unwrap();
+            socket.write_all(&bytes).unwrap();
+            // Wait for the client to hang up, rather than ending the stream ourselves
+            while matches!(std::io::Read::read(&mut socket, &mut [0u8; 64]), Ok(n) if n > 0) {}
+        });
+        Endpoint::Local(path)
+    }
+
+    /// A length prefix too short to hold a header, then what would be mistaken for the next
+    /// frame if the stream carried on
+    pub(crate) fn bad_length() -> Vec<u8> {
+        let mut bytes = 1u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0xFF; 16]);
+        bytes
+    }
+
+    #[test]
+    fn a_bad_frame_length_ends_the_stream() {
+        let endpoint = engine_sending("bad-length-stream", bad_length());
+        let mut stream = subscribe_to(&endpoint, "test").unwrap();
+
+        assert!(matches!(stream.next(), Some(Ok(IpcMessage::Pong(_)))));
+        let e = stream.next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn an_undecodable_message_is_skipped() {
+        let mut bytes = vec![];
+        bytes.write_frame(FrameKind::Json, b"not json").unwrap();
+        write_message(&mut bytes, Codec::Json, &IpcMessage::Pong(Pong)).unwrap();
+        let endpoint = engine_sending("bad-message-stream", bytes);
+        let mut stream = subscribe_to(&endpoint, "test").unwrap();
+
+        assert!(matches!(stream.next(), Some(Ok(IpcMessage::Pong(_)))));
+        let e = stream.next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(matches!(stream.next(), Some(Ok(IpcMessage::Pong(_)))));
+    }
+}
@@ -1,16 +1,432 @@
 use piston_shared::*;
 use serde::{Deserialize, Serialize};
 
+use crate::codec::Codec;
+use std::collections::HashSet;
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum IpcMessage {
+    Request(Request),
+    Response(Response),
     Ping(Ping),
     Pong(Pong),
+    ListPortfolios(ListPortfolios),
+    Portfolios(Portfolios),
+    GetPortfolio(GetPortfolio),
+    GetPnlHistory(GetPnlHistory),
+    PnlHistory(PnlHistory),
+    ListSecurities(ListSecurities),
+    Securities(Securities),
+    GetSecurity(GetSecurity),
+    Security(SecurityPrice),
+    GetPosition(GetPosition),
+    PositionDetail(PositionDetail),
+    InjectTrade(InjectTrade),
+    TradeInjected(TradeInjected),
+    InjectTick(InjectTick),
     PortfolioStats(PortfolioStats),
+    PortfolioDelta(PortfolioDelta),
+    Resync(Resync),
+    PortfolioSnapshots(PortfolioSnapshots),
+    PortfolioRisk(PortfolioRisk),
+    PortfolioBenchmark(PortfolioBenchmark),
+    BookStats(BookStats),
+    Tick(PriceTick),
+    Trade(TradeReport),
+    Alert(Alert),
+    AcknowledgeAlert(AcknowledgeAlert),
+    PortfolioOrders(PortfolioOrders),
+    SubmitOrder(NewOrder),
+    CancelOrder(CancelOrder),
+    ReportFill(Fill),
+    OrderUpdate(Order),
+    PayDividend(PayDividend),
+    DividendPaid(DividendPaid),
+    KillSwitch(KillSwitch),
+    TradingStatus(TradingStatus),
+    RunScenarios(RunScenarios),
+    ScenarioResults(ScenarioResults),
+    Subscribe(Subscription),
+    Unsubscribe(Subscription),
+    Subscriptions(Subscription),
+    GetIpcMetrics(GetIpcMetrics),
+    IpcMetrics(IpcMetrics),
+    Error(IpcError),
+}
+
+impl IpcMessage {
+    /// Whether a subscriber to `topics` is sent this message. Messages outside every topic, such
+    /// as errors, go to every subscriber
+    pub fn is_subscribed(&self, topics: &HashSet<Topic>) -> bool {
+        let portfolio = |code: &str| {
+            topics.contains(&Topic::Portfolios) || topics.contains(&Topic::Portfolio(code.into()))
+        };
+
+        match self {
+            IpcMessage::PortfolioStats(stats) => portfolio(&stats.code),
+            IpcMessage::PortfolioDelta(delta) => portfolio(&delta.code),
+            IpcMessage::PortfolioRisk(risk) => portfolio(&risk.code),
+            IpcMessage::PortfolioBenchmark(benchmark) => portfolio(&benchmark.code),
+            IpcMessage::PortfolioOrders(orders) => portfolio(&orders.code),
+            IpcMessage::OrderUpdate(order) => portfolio(&order.portfolio_code),
+            IpcMessage::BookStats(_) => topics.contains(&Topic::Books),
+            IpcMessage::Alert(_) => topics.contains(&Topic::Alerts),
+            IpcMessage::Trade(_) => topics.contains(&Topic::Trades),
+            IpcMessage::Tick(tick) => {
+                topics.contains(&Topic::Ticks)
+                    || topics.contains(&Topic::Security(tick.security_id))
+            }
+            _ => true,
+        }
+    }
+}
+
+impl IpcMessage {
+    /// Messages with the same key each carry the latest state of the same thing, so a subscriber
+    /// that has fallen behind only needs the newest of them
+    pub fn conflation_key(&self) -> Option<String> {
+        match self {
+            IpcMessage::PortfolioStats(stats) => Some(format!("PortfolioStats/{}", stats.code)),
+            IpcMessage::PortfolioRisk(risk) => Some(format!("PortfolioRisk/{}", risk.code)),
+            IpcMessage::PortfolioBenchmark(benchmark) => {
+                Some(format!("PortfolioBenchmark/{}", benchmark.code))
+            }
+            IpcMessage::PortfolioOrders(orders) => Some(format!("PortfolioOrders/{}", orders.code)),
+            IpcMessage::BookStats(stats) => Some(format!("BookStats/{}", stats.code)),
+            IpcMessage::Tick(tick) => Some(format!("Tick/{}", tick.security_id)),
+            IpcMessage::Ping(_) => Some(String::from("Ping")),
+            _ => None,
+        }
+    }
+}
+
+/// A kind of message on the engine's stream that subscribers can opt in or out of
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Topic {
+    /// Deltas, risk, benchmark and orders of one portfolio
+    Portfolio(String),
+    /// Deltas, risk, benchmark and orders of every portfolio
+    Portfolios,
+    Books,
+    Alerts,
+    /// Trades booked into any portfolio
+    Trades,
+    /// Ticks for one security
+    Security(SecurityId),
+    /// Ticks for every security
+    Ticks,
+}
+
+impl Topic {
+    /// Topics a new subscriber starts with
+    pub fn defaults() -> Vec<Topic> {
+        vec![Topic::Portfolios, Topic::Books, Topic::Alerts]
+    }
+}
+
+/// Sent by a subscriber to add (`Subscribe`) or remove (`Unsubscribe`) topics, and by the engine
+/// in reply (`Subscriptions`) with every topic the subscriber now has
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Subscription {
+    pub topics: Vec<Topic>,
+}
+
+/// A portfolio's stats as of the delta numbered `sequence`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioSnapshot {
+    pub sequence: u64,
+    pub stats: PortfolioStats,
+}
+
+/// What changed in a portfolio's stats since the previous delta. Sequence numbers are per
+/// portfolio and increase by one with every delta, so a client that sees a gap has missed one and
+/// should send `Resync`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioDelta {
+    pub code: String,
+    pub sequence: u64,
+    pub changes: Vec<PortfolioChange>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PortfolioChange {
+    PositionOpened(Position),
+    /// Marked to a new price or reduced in size
+    PositionUpdated(Position),
+    PositionClosed(PositionId),
+    /// PnL, and with it NAV, cash, margin or exposure, or the trade counts or trading state
+    /// changed
+    StatsChanged(Box<PortfolioSummary>),
+}
+
+/// Everything in `PortfolioStats` other than the positions
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioSummary {
+    pub trade_count: u32,
+    pub rejected_trade_count: u32,
+    pub trading_state: TradingState,
+    pub pnl: f64,
+    pub unrealized_pnl: f64,
+    pub attribution: PnlAttribution,
+    pub cash: Vec<CashBalance>,
+    pub nav: f64,
+    pub margin: PortfolioMargin,
+    pub exposure: PortfolioExposure,
+}
+
+/// Request for the latest snapshot of portfolios, so deltas can be applied from there. An empty
+/// `portfolio_codes` resyncs every portfolio. Answered with `PortfolioSnapshots`, and sent on a
+/// subscriber's behalf when it subscribes to portfolios
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Resync {
+    #[serde(default)]
+    pub portfolio_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PortfolioSnapshots {
+    pub snapshots: Vec<PortfolioSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PriceTick {
+    pub security_id: SecurityId,
+    pub ticker: String,
+    pub price: f64,
+}
+
+/// A trade booked into a portfolio
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TradeReport {
+    pub portfolio_code: String,
+    pub security_id: SecurityId,
+    pub ticker: String,
+    pub side: Side,
+    pub size: u32,
+    pub price: f64,
+    /// Zero when opening a position
+    pub realized_pnl: f64,
+}
+
+/// A request tagged with an id chosen by the client. Answered with a `Response` carrying the same
+/// id, so a client can match responses to requests. Requests sent without an id are answered
+/// with the bare response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    pub request: Box<IpcMessage>,
+}
+
+/// The answer to a `Request`, either the typed response or an `Error`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub id: u64,
+    pub response: Box<IpcMessage>,
 }
 
+/// Answered with `Pong`
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ping;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Pong;
+
+/// Request for a portfolio's stats as of now. Answered with its `PortfolioStats`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPortfolio {
+    pub code: String,
+}
+
+/// Request for every portfolio's headline numbers. Answered with `Portfolios`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListPortfolios;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Portfolios {
+    pub portfolios: Vec<PortfolioOverview>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioOverview {
+    pub code: String,
+    pub position_count: usize,
+    pub summary: PortfolioSummary,
+}
+
+/// Request for a portfolio's PnL at the end of each recent stats cycle, oldest first. Answered
+/// with `PnlHistory`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPnlHistory {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PnlHistory {
+    pub code: String,
+    pub points: Vec<PnlPoint>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PnlPoint {
+    /// Seconds since the unix epoch
+    pub at: u64,
+    /// Realized
+    pub pnl: f64,
+    pub unrealized_pnl: f64,
+    pub nav: f64,
+}
+
+/// Request for the security universe. Answered with `Securities`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListSecurities;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Securities {
+    pub securities: Vec<SecurityPrice>,
+}
+
+/// Request for a single security. Answered with `Security`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetSecurity {
+    pub security_id: SecurityId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecurityPrice {
+    pub security: Security,
+    /// `None` until the security has ticked
+    pub price: Option<f64>,
+}
+
+/// Request for a single position, marked at the latest price. Answered with `PositionDetail`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPosition {
+    pub portfolio_code: String,
+    pub position_id: PositionId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PositionDetail {
+    pub portfolio_code: String,
+    pub position: Position,
+}
+
+/// Request to book a trade straight into a portfolio, bypassing the order manager. A buy opens
+/// a new position and a sell reduces the oldest positions first. Answered with `TradeInjected`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InjectTrade {
+    pub portfolio_code: String,
+    pub security_id: SecurityId,
+    pub side: Side,
+    pub size: u32,
+    pub price: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TradeInjected {
+    pub portfolio_code: String,
+    pub security_id: SecurityId,
+    pub side: Side,
+    pub size: u32,
+    pub price: f64,
+    /// The position opened by a buy
+    pub position_id: Option<PositionId>,
+}
+
+/// Request to set a security's price as if it had ticked. Answered with the resulting `Tick`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InjectTick {
+    pub security_id: SecurityId,
+    pub price: f64,
+}
+
+/// Request to revalue portfolios under a set of scenarios without changing their holdings.
+/// An empty `portfolio_codes` runs against every portfolio, and an empty `scenarios` runs the
+/// scenarios configured in the engine
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RunScenarios {
+    #[serde(default)]
+    pub portfolio_codes: Vec<String>,
+    #[serde(default)]
+    pub scenarios: Vec<Scenario>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScenarioResults {
+    pub results: Vec<ScenarioResult>,
+}
+
+/// Request to pay a dividend of `per_share` on a security to every portfolio holding it, in the
+/// security's currency. Answered with `DividendPaid`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PayDividend {
+    pub security_id: SecurityId,
+    pub per_share: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DividendPaid {
+    pub security_id: SecurityId,
+    pub per_share: f64,
+    /// Paid across every portfolio
+    pub total: f64,
+}
+
+/// Request to acknowledge a raised alert. Answered with the updated `Alert`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcknowledgeAlert {
+    pub id: AlertId,
+}
+
+/// Request to cancel a working order. Answered with the updated `Order`, as are `SubmitOrder`
+/// and `ReportFill`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelOrder {
+    pub id: OrderId,
+}
+
+/// Request to freeze, flatten or resume a portfolio. Answered with its `TradingStatus`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KillSwitch {
+    pub portfolio_code: String,
+    pub action: KillSwitchAction,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TradingStatus {
+    pub portfolio_code: String,
+    pub state: TradingState,
+}
+
+/// Request for how the engine's stream is keeping up with its subscribers. Answered with
+/// `IpcMetrics`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetIpcMetrics;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IpcMetrics {
+    pub subscribers: Vec<SubscriberMetrics>,
+    /// Dropped from full queues since the engine started, across every subscriber
+    pub dropped: u64,
+    /// Replaced in queues by newer messages about the same thing
+    pub conflated: u64,
+    pub slow_consumers_disconnected: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscriberMetrics {
+    pub id: u64,
+    pub peer: String,
+    pub codec: Codec,
+    /// Waiting to be written
+    pub queued: u64,
+    pub sent: u64,
+    pub dropped: u64,
+    pub conflated: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IpcError {
+    pub message: String,
+}
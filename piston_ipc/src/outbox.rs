@@ -0,0 +1,306 @@
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::{protocol::FrameKind, transport::Endpoint};
+
+/// What to do with a subscriber whose queue of unsent messages is full because it reads more
+/// slowly than the engine publishes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued message to make room
+    DropOldest,
+    /// Replace a queued message with a newer one about the same thing, such as the risk of the
+    /// same portfolio or the tick of the same security, dropping the oldest message when there
+    /// is none
+    Conflate,
+    /// Detach the subscriber
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PublisherConfig {
+    /// Where subscribers can attach, the local socket by default
+    pub endpoints: Vec<Endpoint>,
+    /// Messages queued per subscriber before the slow consumer policy applies
+    pub queue_capacity: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+}
+
+impl Default for PublisherConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: vec![Endpoint::default()],
+            queue_capacity: 1024,
+            slow_consumer: SlowConsumerPolicy::Conflate,
+        }
+    }
+}
+
+/// An encoded message waiting to be written. Payloads are shared by every subscriber using the
+/// same codec
+#[derive(Debug)]
+pub(crate) struct Outgoing {
+    pub kind: FrameKind,
+    pub payload: Arc<Vec<u8>>,
+    /// Queued messages with the same key can be conflated
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    Queued,
+    /// A queued message was dropped to make room
+    Dropped,
+    /// Replaced a queued message with the same key
+    Conflated,
+    /// The queue is full and the subscriber should be detached
+    Full,
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    /// Broadcast messages, subject to the slow consumer policy
+    messages: VecDeque<Outgoing>,
+    /// Responses to the subscriber's own requests, sent first. They are never dropped, so a
+    /// subscriber with a full queue of them is detached whatever the policy
+    responses: VecDeque<Outgoing>,
+    closed: bool,
+    sent: u64,
+    dropped: u64,
+    conflated: u64,
+}
+
+/// A subscriber's bounded queue of messages, drained by the thread writing to its socket so
+/// publishers never wait on a slow reader
+#[derive(Debug)]
+pub(crate) struct Outbox {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+/// Messages sent, queued and lost for one subscriber
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OutboxCounts {
+    pub queued: u64,
+    pub sent: u64,
+    pub dropped: u64,
+    pub conflated: u64,
+}
+
+impl Outbox {
+    pub fn new(config: &PublisherConfig) -> Self {
+        Self {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            capacity: config.queue_capacity,
+            policy: config.slow_consumer,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().expect("Failed to lock outbox")
+    }
+
+    /// Queue a broadcast message, applying the slow consumer policy when the queue is full
+    pub fn push(&self, message: Outgoing) -> Pushed {
+        let mut queue = self.lock();
+        if queue.closed {
+            return Pushed::Closed;
+        }
+
+        let mut pushed = Pushed::Queued;
+        if self.policy == SlowConsumerPolicy::Conflate && message.key.is_some() {
+            if let Some(queued) = queue.messages.iter_mut().find(|m| m.key == message.key) {
+                *queued = message;
+                queue.conflated += 1;
+                return Pushed::Conflated;
+            }
+        }
+        if queue.messages.len() >= self.capacity {
+            if self.policy == SlowConsumerPolicy::Disconnect {
+                return Pushed::Full;
+            }
+            queue.messages.pop_front();
+            queue.dropped += 1;
+            pushed = Pushed::Dropped;
+        }
+
+        queue.messages.push_back(message);
+        self.ready.notify_one();
+        pushed
+    }
+
+    /// Queue a response, which is never dropped. A subscriber that sends requests faster than
+    /// it reads the responses fills the queue, and should be detached
+    pub fn respond(&self, message: Outgoing) -> Pushed {
+        let mut queue = self.lock();
+        if queue.closed {
+            return Pushed::Closed;
+        }
+        if queue.responses.len() >= self.capacity {
+            return Pushed::Full;
+        }
+
+        queue.responses.push_back(message);
+        self.ready.notify_one();
+        Pushed::Queued
+    }
+
+    /// Wait for the next message to write, or `None` once the outbox is closed
+    pub fn pop(&self) -> Option<Outgoing> {
+        let mut queue = self.lock();
+        loop {
+            if queue.closed {
+                return None;
+            }
+            if let Some(message) = queue
+                .responses
+                .pop_front()
+                .or_else(|| queue.messages.pop_front())
+            {
+                return Some(message);
+            }
+            queue = self.ready.wait(queue).expect("Failed to lock outbox");
+        }
+    }
+
+    /// Count a message the writer has finished writing
+    pub fn sent(&self) {
+        self.lock().sent += 1;
+    }
+
+    /// Stop accepting messages and wake the writer so it can exit
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+
+    pub fn counts(&self) -> OutboxCounts {
+        let queue = self.lock();
+        OutboxCounts {
+            queued: (queue.messages.len() + queue.responses.len()) as u64,
+            sent: queue.sent,
+            dropped: queue.dropped,
+            conflated: queue.conflated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox(policy: SlowConsumerPolicy) -> Outbox {
+        Outbox::new(&PublisherConfig {
+            queue_capacity: 2,
+            slow_consumer: policy,
+            ..PublisherConfig::default()
+        })
+    }
+
+    fn message(payload: u8, key: Option<&str>) -> Outgoing {
+        Outgoing {
+            kind: FrameKind::Json,
+            payload: Arc::new(vec![payload]),
+            key: key.map(String::from),
+        }
+    }
+
+    /// Everything queued, in the order it would be written
+    fn drain(outbox: &Outbox) -> Vec<u8> {
+        let mut payloads = vec![];
+        while outbox.counts().queued > 0 {
+            let message = outbox.pop().expect("Outbox is open");
+            payloads.push(message.payload[0]);
+            outbox.sent();
+        }
+        payloads
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_new_messages() {
+        let outbox = outbox(SlowConsumerPolicy::DropOldest);
+        assert_eq!(outbox.push(message(1, None)), Pushed::Queued);
+        assert_eq!(outbox.push(message(2, None)), Pushed::Queued);
+        assert_eq!(outbox.push(message(3, None)), Pushed::Dropped);
+
+        assert_eq!(drain(&outbox), vec![2, 3]);
+        let counts = outbox.counts();
+        assert_eq!(counts.dropped, 1);
+        assert_eq!(counts.sent, 2);
+    }
+
+    #[test]
+    fn conflate_replaces_messages_with_the_same_key() {
+        let outbox = outbox(SlowConsumerPolicy::Conflate);
+        assert_eq!(outbox.push(message(1, Some("AAPL"))), Pushed::Queued);
+        assert_eq!(outbox.push(message(2, Some("MSFT"))), Pushed::Queued);
+        assert_eq!(outbox.push(message(3, Some("AAPL"))), Pushed::Conflated);
+        assert_eq!(outbox.counts().queued, 2);
+        // Full with no message to replace, so the oldest goes
+        assert_eq!(outbox.push(message(4, None)), Pushed::Dropped);
+
+        assert_eq!(drain(&outbox), vec![2, 4]);
+        let counts = outbox.counts();
+        assert_eq!(counts.conflated, 1);
+        assert_eq!(counts.dropped, 1);
+    }
+
+    #[test]
+    fn conflate_only_applies_under_its_policy() {
+        let outbox = outbox(SlowConsumerPolicy::DropOldest);
+        outbox.push(message(1, Some("AAPL")));
+        assert_eq!(outbox.push(message(2, Some("AAPL"))), Pushed::Queued);
+        assert_eq!(drain(&outbox), vec![1, 2]);
+    }
+
+    #[test]
+    fn disconnect_reports_a_full_queue() {
+        let outbox = outbox(SlowConsumerPolicy::Disconnect);
+        outbox.push(message(1, Some("AAPL")));
+        outbox.push(message(2, Some("AAPL")));
+        assert_eq!(outbox.push(message(3, None)), Pushed::Full);
+
+        assert_eq!(drain(&outbox), vec![1, 2]);
+        assert_eq!(outbox.counts().dropped, 0);
+    }
+
+    #[test]
+    fn responses_go_first_and_are_capped() {
+        let outbox = outbox(SlowConsumerPolicy::DropOldest);
+        outbox.push(message(1, None));
+        assert_eq!(outbox.respond(message(2, None)), Pushed::Queued);
+        assert_eq!(outbox.respond(message(3, None)), Pushed::Queued);
+        assert_eq!(outbox.respond(message(4, None)), Pushed::Full);
+
+        assert_eq!(drain(&outbox), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn messages_are_counted_once_written() {
+        let outbox = outbox(SlowConsumerPolicy::DropOldest);
+        outbox.push(message(1, None));
+        outbox.pop().expect("Outbox is open");
+        assert_eq!(outbox.counts().sent, 0);
+        outbox.sent();
+        assert_eq!(outbox.counts().sent, 1);
+    }
+
+    #[test]
+    fn a_closed_outbox_takes_nothing() {
+        let outbox = outbox(SlowConsumerPolicy::DropOldest);
+        outbox.push(message(1, None));
+        outbox.close();
+
+        assert_eq!(outbox.push(message(2, None)), Pushed::Closed);
+        assert_eq!(outbox.respond(message(3, None)), Pushed::Closed);
+        assert!(outbox.pop().is_none());
+    }
+}
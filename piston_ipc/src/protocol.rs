@@ -0,0 +1,395 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{negotiate, supported_codecs, Codec},
+    messages::IpcMessage,
+};
+
+/// Bumped whenever the wire format or the messages change incompatibly
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Frames larger than this are rejected rather than allocated
+pub(crate) const MAX_FRAME_LENGTH: u32 = 64 * 1024 * 1024;
+
+/// Bytes following the length prefix before the payload: version and frame kind
+const HEADER_LENGTH: u32 = 3;
+
+/// Every frame on the wire is a big-endian `u32` length of the rest of the frame, then a `u16`
+/// protocol version, a `u8` frame kind and the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// First frame sent by each side of a connection, carrying a JSON `Hello`
+    Hello = 1,
+    /// A JSON `IpcMessage`
+    Json = 2,
+    /// A MessagePack `IpcMessage`
+    MessagePack = 3,
+}
+
+impl From<Codec> for FrameKind {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::Json => FrameKind::Json,
+            Codec::MessagePack => FrameKind::MessagePack,
+        }
+    }
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = Error;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(FrameKind::Hello),
+            2 => Ok(FrameKind::Json),
+            3 => Ok(FrameKind::MessagePack),
+            other => Err(invalid(format!("unknown frame kind {}", other))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub version: u16,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+/// Exchanged by both ends when a connection is opened, so either can hang up on a peer speaking
+/// a different protocol version, and to agree on a codec
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u16,
+    /// Name of the process on the other end, for logging
+    pub peer: String,
+    /// Codecs the sender can decode
+    #[serde(default = "default_codecs")]
+    pub codecs: Vec<Codec>,
+}
+
+fn default_codecs() -> Vec<Codec> {
+    vec![Codec::Json]
+}
+
+/// Reads whole frames off one end of a connection, however the transport delimits them
+pub trait FrameRead {
+    /// The next frame, or `None` if the other end hung up between frames
+    fn read_frame(&mut self) -> std::io::Result<Option<Frame>>;
+}
+
+/// Writes whole frames to one end of a connection
+pub trait FrameWrite {
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> std::io::Result<()>;
+}
+
+/// Byte streams delimit frames with their length prefix
+impl<R: Read> FrameRead for R {
+    fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        read_frame(self)
+    }
+}
+
+impl<W: Write> FrameWrite for W {
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> std::io::Result<()> {
+        write_frame(self, kind, payload)
+    }
+}
+
+/// A frame without its length prefix, for transports that delimit frames themselves
+pub(crate) fn encode_frame(kind: FrameKind, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    let length = u32::try_from(payload.len())
+        .ok()
+        .and_then(|l| l.checked_add(HEADER_LENGTH))
+        .filter(|l| *l <= MAX_FRAME_LENGTH)
+        .ok_or_else(|| invalid(format!("{} byte payload is too large", payload.len())))?;
+
+    let mut frame = Vec::with_capacity(length as usize);
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.push(kind as u8);
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Split a frame without its length prefix into its header and payload
+pub(crate) fn decode_frame(mut frame: Vec<u8>) -> std::io::Result<Frame> {
+    if frame.len() < HEADER_LENGTH as usize {
+        return Err(invalid(format!("{} byte frame is too short", frame.len())));
+    }
+    let version = u16::from_be_bytes([frame[0], frame[1]]);
+    let kind = FrameKind::try_from(frame[2])?;
+    frame.drain(..HEADER_LENGTH as usize);
+
+    Ok(Frame {
+        version,
+        kind,
+        payload: frame,
+    })
+}
+
+pub fn write_frame(
+    stream: &mut impl Write,
+    kind: FrameKind,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let frame = encode_frame(kind, payload)?;
+    let mut bytes = Vec::with_capacity(4 + frame.len());
+    bytes.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&frame);
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+/// Read a single frame, or `None` if the other end hung up between frames. The payload is always
+/// read in full, so a frame that fails to decode does not affect the ones after it. A length that
+/// cannot be trusted leaves no way to find the next frame, so ends the connection
+pub fn read_frame(stream: &mut impl Read) -> std::io::Result<Option<Frame>> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let length = u32::from_be_bytes(length);
+    if !(HEADER_LENGTH..=MAX_FRAME_LENGTH).contains(&length) {
+        return Err(violation(format!("bad frame length {}", length)));
+    }
+
+    let mut frame = vec![0u8; length as usize];
+    stream.read_exact(&mut frame)?;
+
+    decode_frame(frame).map(Some)
+}
+
+/// Send our `Hello` and wait for the peer's, failing if it speaks another protocol version.
+/// Returns the peer's `Hello` and the codec to send messages with
+pub fn handshake(
+    stream: &mut (impl FrameRead + FrameWrite),
+    peer: &str,
+) -> std::io::Result<(Hello, Codec)> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        peer: peer.to_string(),
+        codecs: supported_codecs(),
+    };
+    stream.write_frame(FrameKind::Hello, &serde_json::to_vec(&hello)?)?;
+
+    let frame = stream.read_frame()?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::UnexpectedEof,
+            "Connection closed during handshake",
+        )
+    })?;
+    if frame.kind != FrameKind::Hello {
+        return Err(invalid(format!("expected Hello, got {:?}", frame.kind)));
+    }
+    let hello: Hello = serde_json::from_slice(&frame.payload)?;
+    if hello.version != PROTOCOL_VERSION || frame.version != PROTOCOL_VERSION {
+        return Err(invalid(format!(
+            "{} speaks protocol version {}, expected {}",
+            hello.peer, hello.version, PROTOCOL_VERSION
+        )));
+    }
+
+    let codec = negotiate(&supported_codecs(), &hello.codecs).ok_or_else(|| {
+        invalid(format!(
+            "{} only supports {:?}, expected one of {:?}",
+            hello.peer,
+            hello.codecs,
+            supported_codecs()
+        ))
+    })?;
+
+    Ok((hello, codec))
+}
+
+pub fn write_message(
+    stream: &mut impl FrameWrite,
+    codec: Codec,
+    message: &IpcMessage,
+) -> std::io::Result<()> {
+    stream.write_frame(codec.into(), &codec.encode(message)?)
+}
+
+/// Read the next message, or `None` if the other end hung up. A frame that is not a valid message
+/// is returned as an `InvalidData` error, leaving the stream at the start of the next frame. Any
+/// other error means the stream can no longer be read
+pub fn read_message(stream: &mut impl FrameRead) -> std::io::Result<Option<IpcMessage>> {
+    let Some(frame) = stream.read_frame()? else {
+        return Ok(None);
+    };
+    if frame.version != PROTOCOL_VERSION {
+        return Err(invalid(format!(
+            "message uses protocol version {}, expected {}",
+            frame.version, PROTOCOL_VERSION
+        )));
+    }
+    let codec = match frame.kind {
+        FrameKind::Json => Codec::Json,
+        FrameKind::MessagePack => Codec::MessagePack,
+        FrameKind::Hello => return Err(invalid(String::from("unexpected Hello frame"))),
+    };
+
+    Ok(Some(codec.decode(&frame.payload)?))
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// A frame that breaks the protocol, after which the connection can no longer be read
+pub(crate) fn violation(message: String) -> Error {
+    Error::new(ErrorKind::ConnectionAborted, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Reads a canned byte stream and records what is written
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Loopback {
+        fn new(input: Vec<u8>) -> Self {
+            Self {
+                input: Cursor::new(input),
+                output: vec![],
+            }
+        }
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hello_frame(version: u16, codecs: Vec<Codec>) -> Vec<u8> {
+        let hello = Hello {
+            version,
+            peer: String::from("test"),
+            codecs,
+        };
+        let mut bytes = vec![];
+        write_frame(
+            &mut bytes,
+            FrameKind::Hello,
+            &serde_json::to_vec(&hello).unwrap(),
+        )
+        .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut bytes = vec![];
+        write_frame(&mut bytes, FrameKind::Json, b"{}").unwrap();
+        write_frame(&mut bytes, FrameKind::MessagePack, b"").unwrap();
+
+        let mut stream = Cursor::new(bytes);
+        let frame = read_frame(&mut stream).unwrap().unwrap();
+        assert_eq!(frame.version, PROTOCOL_VERSION);
+        assert_eq!(frame.kind, FrameKind::Json);
+        assert_eq!(frame.payload, b"{}");
+        let frame = read_frame(&mut stream).unwrap().unwrap();
+        assert_eq!(frame.kind, FrameKind::MessagePack);
+        assert!(frame.payload.is_empty());
+        assert!(read_frame(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn lengths_outside_the_bounds_are_rejected() {
+        for length in [0, HEADER_LENGTH - 1, MAX_FRAME_LENGTH + 1, u32::MAX] {
+            let mut stream = Cursor::new(length.to_be_bytes().to_vec());
+            let e = read_frame(&mut stream).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ConnectionAborted, "length {}", length);
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut bytes = vec![];
+        write_frame(&mut bytes, FrameKind::Json, b"{\"type\":\"Ping\"}").unwrap();
+        bytes.truncate(bytes.len() - 1);
+
+        let e = read_frame(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        assert!(decode_frame(vec![0, 1]).is_err());
+        assert!(decode_frame(vec![0, 1, 9]).is_err());
+        let frame = decode_frame(vec![0, 1, 2]).unwrap();
+        assert_eq!(frame.kind, FrameKind::Json);
+        assert!(frame.payload.is_empty());
+    }
+
+    #[test]
+    fn oversized_payloads_are_not_encoded() {
+        let payload = vec![0u8; (MAX_FRAME_LENGTH - HEADER_LENGTH) as usize];
+        assert!(encode_frame(FrameKind::Json, &payload).is_ok());
+        let payload = vec![0u8; (MAX_FRAME_LENGTH - HEADER_LENGTH + 1) as usize];
+        assert!(encode_frame(FrameKind::Json, &payload).is_err());
+    }
+
+    #[test]
+    fn the_most_compact_common_codec_is_negotiated() {
+        let both = [Codec::Json, Codec::MessagePack];
+        assert_eq!(negotiate(&both, &both), Some(Codec::MessagePack));
+        assert_eq!(negotiate(&both, &[Codec::Json]), Some(Codec::Json));
+        assert_eq!(negotiate(&[Codec::Json], &both), Some(Codec::Json));
+        assert_eq!(negotiate(&[Codec::Json], &[Codec::MessagePack]), None);
+    }
+
+    #[test]
+    fn handshake_agrees_on_a_codec() {
+        let mut stream = Loopback::new(hello_frame(PROTOCOL_VERSION, vec![Codec::Json]));
+        let (hello, codec) = handshake(&mut stream, "engine").unwrap();
+        assert_eq!(hello.peer, "test");
+        assert_eq!(codec, Codec::Json);
+
+        let sent = read_frame(&mut Cursor::new(stream.output))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent.kind, FrameKind::Hello);
+        let sent: Hello = serde_json::from_slice(&sent.payload).unwrap();
+        assert_eq!(sent.peer, "engine");
+        assert_eq!(sent.codecs, supported_codecs());
+    }
+
+    #[test]
+    fn handshake_rejects_other_versions_and_codecs() {
+        let mut stream = Loopback::new(hello_frame(PROTOCOL_VERSION + 1, vec![Codec::Json]));
+        let e = handshake(&mut stream, "engine").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        let mut stream = Loopback::new(hello_frame(PROTOCOL_VERSION, vec![]));
+        let e = handshake(&mut stream, "engine").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        let e = handshake(&mut Loopback::new(vec![]), "engine").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn hello_codecs_default_to_json() {
+        let hello: Hello = serde_json::from_str(r#"{"version":1,"peer":"old"}"#).unwrap();
+        assert_eq!(hello.codecs, vec![Codec::Json]);
+    }
+}
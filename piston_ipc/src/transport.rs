@@ -0,0 +1,280 @@
+use serde::Deserialize;
+use std::{
+    fmt,
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    protocol::{Frame, FrameKind, FrameRead, FrameWrite},
+    websocket::{self, WsReader, WsWriter},
+};
+
+const LOCAL_SCHEME: &str = "unix:";
+const TCP_SCHEME: &str = "tcp://";
+const WEBSOCKET_SCHEME: &str = "ws://";
+
+/// Where the message stream is served, written as `unix:/tmp/piston-ipc.sock`,
+/// `tcp://127.0.0.1:7400` or `ws://127.0.0.1:7401`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Endpoint {
+    /// A Unix domain socket at this path
+    Local(PathBuf),
+    /// A TCP address to bind or connect to
+    Tcp(String),
+    /// A TCP address serving the stream to browsers, one protocol frame per binary WebSocket
+    /// message
+    WebSocket(String),
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint::Local(PathBuf::from("/tmp/piston-ipc.sock"))
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(LOCAL_SCHEME) {
+            Ok(Endpoint::Local(PathBuf::from(path)))
+        } else if let Some(address) = s.strip_prefix(TCP_SCHEME) {
+            Ok(Endpoint::Tcp(address.to_string()))
+        } else if let Some(address) = s.strip_prefix(WEBSOCKET_SCHEME) {
+            Ok(Endpoint::WebSocket(
+                address.trim_end_matches('/').to_string(),
+            ))
+        } else {
+            Err(format!(
+                "Unknown endpoint {}, expected unix:<path>, tcp://<address> or ws://<address>",
+                s
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Local(path) => write!(f, "{}{}", LOCAL_SCHEME, path.display()),
+            Endpoint::Tcp(address) => write!(f, "{}{}", TCP_SCHEME, address),
+            Endpoint::WebSocket(address) => write!(f, "{}{}", WEBSOCKET_SCHEME, address),
+        }
+    }
+}
+
+/// A connected socket, whichever transport it came from
+#[derive(Debug)]
+pub enum Socket {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Socket {
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Socket::Unix(s) => s.try_clone().map(Socket::Unix),
+            Socket::Tcp(s) => s.try_clone().map(Socket::Tcp),
+        }
+    }
+
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        match self {
+            Socket::Unix(s) => s.shutdown(Shutdown::Both),
+            Socket::Tcp(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Socket::Unix(s) => s.set_read_timeout(timeout),
+            Socket::Tcp(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    /// The address of the other end, for logging
+    pub fn peer_address(&self) -> String {
+        match self {
+            Socket::Unix(_) => String::from("local"),
+            Socket::Tcp(s) => s
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| String::from("unknown")),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Unix(s) => s.read(buf),
+            Socket::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Unix(s) => s.write(buf),
+            Socket::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Socket::Unix(s) => s.flush(),
+            Socket::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+/// Accepts connections on an `Endpoint`
+#[derive(Debug)]
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Listen on an endpoint, replacing any socket file left behind at a local path
+    pub fn bind(endpoint: &Endpoint) -> std::io::Result<Self> {
+        match endpoint {
+            Endpoint::Local(path) => {
+                let path = Path::new(path);
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            Endpoint::Tcp(address) | Endpoint::WebSocket(address) => {
+                TcpListener::bind(address).map(Listener::Tcp)
+            }
+        }
+    }
+
+    pub fn accept(&self) -> std::io::Result<Socket> {
+        match self {
+            Listener::Unix(l) => l.accept().map(|(s, _)| Socket::Unix(s)),
+            Listener::Tcp(l) => {
+                let (stream, _) = l.accept()?;
+                stream.set_nodelay(true)?;
+                Ok(Socket::Tcp(stream))
+            }
+        }
+    }
+}
+
+/// The half of a connection frames are read from
+pub enum Reader {
+    Stream(BufReader<Socket>),
+    WebSocket(WsReader),
+}
+
+/// The half of a connection frames are written to
+pub enum Writer {
+    Stream(Socket),
+    WebSocket(WsWriter),
+}
+
+impl FrameRead for Reader {
+    fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        match self {
+            Reader::Stream(r) => r.read_frame(),
+            Reader::WebSocket(r) => r.read_frame(),
+        }
+    }
+}
+
+impl FrameWrite for Writer {
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> std::io::Result<()> {
+        match self {
+            Writer::Stream(w) => w.write_frame(kind, payload),
+            Writer::WebSocket(w) => w.write_frame(kind, payload),
+        }
+    }
+}
+
+/// A connection split into halves that can be used from separate threads, and a handle to hang
+/// up on the other end with
+pub struct Channel {
+    pub reader: Reader,
+    pub writer: Writer,
+    pub socket: Socket,
+}
+
+impl Channel {
+    /// Set up a connection accepted on `endpoint`, answering the WebSocket upgrade request on
+    /// WebSocket endpoints
+    pub fn accept(socket: Socket, endpoint: &Endpoint) -> std::io::Result<Self> {
+        let (reader, writer) = match endpoint {
+            Endpoint::WebSocket(_) => {
+                let (reader, writer) = websocket::accept(socket.try_clone()?)?;
+                (Reader::WebSocket(reader), Writer::WebSocket(writer))
+            }
+            _ => (
+                Reader::Stream(BufReader::new(socket.try_clone()?)),
+                Writer::Stream(socket.try_clone()?),
+            ),
+        };
+
+        Ok(Self {
+            reader,
+            writer,
+            socket,
+        })
+    }
+
+    /// Connect to `endpoint`. WebSocket endpoints are for browsers, other clients connect to a
+    /// local or TCP endpoint
+    pub fn connect(endpoint: &Endpoint) -> std::io::Result<Self> {
+        let socket = match endpoint {
+            Endpoint::Local(path) => Socket::Unix(UnixStream::connect(path)?),
+            Endpoint::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                // Messages are small and latency matters more than packet count
+                stream.set_nodelay(true)?;
+                Socket::Tcp(stream)
+            }
+            Endpoint::WebSocket(_) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Cannot connect to {}, use a tcp:// endpoint", endpoint),
+                ))
+            }
+        };
+
+        Ok(Self {
+            reader: Reader::Stream(BufReader::new(socket.try_clone()?)),
+            writer: Writer::Stream(socket.try_clone()?),
+            socket,
+        })
+    }
+}
+
+/// Handshakes read and write on the same thread, before the halves are handed out
+impl FrameRead for Channel {
+    fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        self.reader.read_frame()
+    }
+}
+
+impl FrameWrite for Channel {
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> std::io::Result<()> {
+        self.writer.write_frame(kind, payload)
+    }
+}
@@ -0,0 +1,367 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    protocol::{
+        decode_frame, encode_frame, violation, Frame, FrameKind, FrameRead, FrameWrite,
+        MAX_FRAME_LENGTH,
+    },
+    transport::Socket,
+};
+
+/// Appended to the client's key to prove the server understood the upgrade request (RFC 6455)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Upgrade requests with a longer head than this are rejected
+const MAX_REQUEST_LENGTH: usize = 16 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Answer a browser's WebSocket upgrade request on a newly accepted socket, returning the halves
+/// protocol frames are then read from and written to
+pub(crate) fn accept(socket: Socket) -> std::io::Result<(WsReader, WsWriter)> {
+    let mut reader = BufReader::new(socket.try_clone()?);
+    let mut key = None;
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line)?;
+        length += read;
+        if read == 0 || length > MAX_REQUEST_LENGTH {
+            return Err(invalid(String::from(
+                "Incomplete WebSocket upgrade request",
+            )));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Sec-WebSocket-Key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut socket = socket;
+    let Some(key) = key else {
+        let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+        return Err(invalid(String::from("Not a WebSocket upgrade request")));
+    };
+    let accept = STANDARD.encode(
+        sha1_smol::Sha1::from(format!("{}{}", key, ACCEPT_GUID))
+            .digest()
+            .bytes(),
+    );
+    socket.write_all(
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept
+        )
+        .as_bytes(),
+    )?;
+
+    let writer = WsWriter(Arc::new(Mutex::new(socket)));
+    Ok((
+        WsReader {
+            reader,
+            writer: writer.clone(),
+        },
+        writer,
+    ))
+}
+
+/// Reads protocol frames sent as binary WebSocket messages, answering the browser's pings and
+/// close on the way
+pub struct WsReader {
+    reader: BufReader<Socket>,
+    /// Control frames are answered through the same writer as the data
+    writer: WsWriter,
+}
+
+impl WsReader {
+    /// The next whole message, or `None` once the browser closes the connection
+    fn read_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut message = vec![];
+        loop {
+            let mut head = [0u8; 2];
+            match self.reader.read_exact(&mut head) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0F;
+            // Browsers must mask every frame they send (RFC 6455 5.1)
+            if head[1] & 0x80 == 0 {
+                return Err(violation(String::from("unmasked WebSocket frame")));
+            }
+            let length = match head[1] & 0x7F {
+                126 => {
+                    let mut length = [0u8; 2];
+                    self.reader.read_exact(&mut length)?;
+                    u16::from_be_bytes(length) as u64
+                }
+                127 => {
+                    let mut length = [0u8; 8];
+                    self.reader.read_exact(&mut length)?;
+                    let length = u64::from_be_bytes(length);
+                    if length & (1 << 63) != 0 {
+                        return Err(violation(String::from("bad WebSocket frame length")));
+                    }
+                    length
+                }
+                length => length as u64,
+            };
+            if opcode & 0x8 != 0 && (length > 125 || !fin) {
+                return Err(violation(format!(
+                    "{} byte WebSocket control frame is too large or fragmented",
+                    length
+                )));
+            }
+            if (message.len() as u64).saturating_add(length) > MAX_FRAME_LENGTH as u64 {
+                return Err(violation(format!(
+                    "{} byte WebSocket frame is too large",
+                    length
+                )));
+            }
+
+            let mut mask = [0u8; 4];
+            self.reader.read_exact(&mut mask)?;
+            let mut payload = vec![0u8; length as usize];
+            self.reader.read_exact(&mut payload)?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            match opcode {
+                CONTINUATION | TEXT | BINARY => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(Some(message));
+                    }
+                }
+                PING => self.writer.send(PONG, &payload)?,
+                PONG => {}
+                CLOSE => {
+                    // Echo the status code back, as the closing handshake expects
+                    let _ = self.writer.send(CLOSE, &payload[..payload.len().min(2)]);
+                    return Ok(None);
+                }
+                other => return Err(violation(format!("unknown WebSocket opcode {}", other))),
+            }
+        }
+    }
+}
+
+impl FrameRead for WsReader {
+    fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        match self.read_message()? {
+            Some(message) => decode_frame(message).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Writes protocol frames as binary WebSocket messages. Clones share the socket, so the reader's
+/// control frames never land in the middle of a message
+#[derive(Clone)]
+pub struct WsWriter(Arc<Mutex<Socket>>);
+
+impl WsWriter {
+    fn send(&self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        // Servers never mask their frames
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        let mut socket = self.0.lock().expect("Failed to lock WebSocket");
+        socket.write_all(&frame)?;
+        socket.flush()
+    }
+}
+
+impl FrameWrite for WsWriter {
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> std::io::Result<()> {
+        self.send(BINARY, &encode_frame(kind, payload)?)
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    /// The key and accept value from the example in RFC 6455 1.3
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+    const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+    /// A browser's end of an upgraded connection and the engine's halves of it
+    fn connect() -> (UnixStream, WsReader, WsWriter) {
+        let (mut browser, engine) = UnixStream::pair().unwrap();
+        write!(
+            browser,
+            "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            KEY
+        )
+        .unwrap();
+        let (reader, writer) = accept(Socket::Unix(engine)).unwrap();
+
+        let response = read_head(&mut browser);
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert!(response.contains(&format!("Sec-WebSocket-Accept: {}\r\n", ACCEPT)));
+        (browser, reader, writer)
+    }
+
+    fn read_head(browser: &mut UnixStream) -> String {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            browser.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// A frame as a browser sends it, masked
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// The opcode and payload of the next frame the engine sent
+    fn read_sent(browser: &mut UnixStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        browser.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80);
+        assert_eq!(head[1] & 0x80, 0, "servers must not mask");
+        let mut payload = vec![0u8; (head[1] & 0x7F) as usize];
+        browser.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    fn rejected(bytes: &[u8]) -> Error {
+        let (mut browser, mut reader, _) = connect();
+        browser.write_all(bytes).unwrap();
+        reader.read_frame().unwrap_err()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let (mut browser, mut reader, mut writer) = connect();
+        let message = encode_frame(FrameKind::Json, b"{\"type\":\"Ping\"}").unwrap();
+        let (first, rest) = message.split_at(4);
+        browser.write_all(&masked(false, BINARY, first)).unwrap();
+        browser
+            .write_all(&masked(true, CONTINUATION, rest))
+            .unwrap();
+
+        let frame = reader.read_frame().unwrap().unwrap();
+        assert_eq!(frame.kind, FrameKind::Json);
+        assert_eq!(frame.payload, b"{\"type\":\"Ping\"}");
+
+        writer.write_frame(FrameKind::Json, b"{}").unwrap();
+        let (opcode, payload) = read_sent(&mut browser);
+        assert_eq!(opcode, BINARY);
+        assert_eq!(payload, encode_frame(FrameKind::Json, b"{}").unwrap());
+    }
+
+    #[test]
+    fn pings_are_answered_and_close_ends_the_stream() {
+        let (mut browser, mut reader, _) = connect();
+        browser.write_all(&masked(true, PING, b"hi")).unwrap();
+        browser
+            .write_all(&masked(true, CLOSE, &[0x03, 0xE8, b'x']))
+            .unwrap();
+
+        assert!(reader.read_frame().unwrap().is_none());
+        assert_eq!(read_sent(&mut browser), (PONG, b"hi".to_vec()));
+        assert_eq!(read_sent(&mut browser), (CLOSE, vec![0x03, 0xE8]));
+    }
+
+    #[test]
+    fn a_request_without_a_key_is_refused() {
+        let (mut browser, engine) = UnixStream::pair().unwrap();
+        browser
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let e = accept(Socket::Unix(engine)).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(read_head(&mut browser).starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn unmasked_frames_are_rejected() {
+        let e = rejected(&[0x80 | BINARY, 0]);
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        assert!(e.to_string().contains("unmasked"));
+    }
+
+    #[test]
+    fn a_64_bit_length_with_the_top_bit_set_is_rejected() {
+        let mut bytes = vec![0x80 | BINARY, 0x80 | 127];
+        bytes.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        let e = rejected(&bytes);
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        assert!(e.to_string().contains("bad WebSocket frame length"));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut bytes = vec![0x80 | BINARY, 0x80 | 127];
+        bytes.extend_from_slice(&(MAX_FRAME_LENGTH as u64 + 1).to_be_bytes());
+        let e = rejected(&bytes);
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        assert!(e.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn oversized_or_fragmented_control_frames_are_rejected() {
+        for frame in [masked(true, PING, &[0; 126]), masked(false, PING, b"hi")] {
+            let e = rejected(&frame);
+            assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+            assert!(e.to_string().contains("control frame"));
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        let e = rejected(&masked(true, 0x3, b""));
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        assert!(e.to_string().contains("unknown WebSocket opcode 3"));
+    }
+}
@@ -1,24 +1,506 @@
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
 
 pub type SecurityId = u32;
 pub type PositionId = u32;
+pub type OrderId = u32;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PortfolioStats {
     pub code: String,
     pub positions: Vec<Position>,
     pub trade_count: u32,
+    pub rejected_trade_count: u32,
+    pub trading_state: TradingState,
     pub pnl: f64,
     pub unrealized_pnl: f64,
+    pub attribution: PnlAttribution,
+    pub cash: Vec<CashBalance>,
+    /// Cash plus market value across every currency. FX is not modelled, so currencies are
+    /// summed at par
+    pub nav: f64,
+    pub margin: PortfolioMargin,
+    pub exposure: PortfolioExposure,
 }
 
+/// Market value a portfolio holds, netted per security before being split into long and short.
+/// Long and short are both positive, so gross is their sum and net their difference
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioExposure {
+    pub gross: f64,
+    pub net: f64,
+    pub long: f64,
+    pub short: f64,
+    /// The security with the largest exposure, if anything is held
+    pub largest: Option<SecurityExposure>,
+    pub by_group: Vec<GroupExposure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SecurityExposure {
+    pub security_id: SecurityId,
+    pub ticker: String,
+    pub market_value: f64,
+    /// Share of the portfolio's gross exposure
+    pub concentration: f64,
+}
+
+/// Exposure to every security tagged with `group` = `name`, e.g. `currency` = `USD`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupExposure {
+    pub group: String,
+    pub name: String,
+    pub gross: f64,
+    pub net: f64,
+    pub long: f64,
+    pub short: f64,
+    /// Share of the portfolio's gross exposure
+    pub concentration: f64,
+}
+
+/// Margin requirements against a portfolio's positions, covered by its NAV
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioMargin {
+    pub initial_requirement: f64,
+    pub maintenance_requirement: f64,
+    /// NAV above the initial requirement, negative when short of it
+    pub initial_excess: f64,
+    /// NAV above the maintenance requirement, negative when short of it
+    pub maintenance_excess: f64,
+    /// Gross market value over NAV, zero when NAV is not positive
+    pub leverage: f64,
+    pub state: MarginState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarginState {
+    Healthy,
+    /// NAV is below the initial requirement but still covers the maintenance requirement
+    BelowInitial,
+    /// NAV is below the maintenance requirement
+    MarginCall,
+}
+
+/// A portfolio's holdings in one currency. Fees and dividends are totals since startup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CashBalance {
+    pub currency: String,
+    pub cash: f64,
+    pub market_value: f64,
+    pub nav: f64,
+    pub fees: f64,
+    pub dividends: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OrderType {
+    Market,
+    Limit(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+}
+
+impl OrderStatus {
+    /// Whether the order can still be filled
+    pub fn is_working(&self) -> bool {
+        matches!(self, OrderStatus::New | OrderStatus::PartiallyFilled)
+    }
+}
+
+/// An order as submitted by a client, before the order manager has accepted it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewOrder {
+    pub portfolio_code: String,
+    pub security_id: SecurityId,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub quantity: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Order {
+    pub id: OrderId,
+    pub portfolio_code: String,
+    pub security: Security,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub quantity: u32,
+    pub filled_quantity: u32,
+    pub average_fill_price: f64,
+    pub status: OrderStatus,
+    /// Why the order was rejected, or why the rest of a partially filled order was cancelled
+    pub reject_reason: Option<String>,
+}
+
+impl Order {
+    pub fn remaining_quantity(&self) -> u32 {
+        self.quantity - self.filled_quantity
+    }
+}
+
+/// An execution against a working order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    pub order_id: OrderId,
+    pub quantity: u32,
+    pub price: f64,
+}
+
+/// A portfolio's working orders, with the market value they would add or remove if filled
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioOrders {
+    pub code: String,
+    pub orders: Vec<Order>,
+    pub buy_exposure: f64,
+    pub sell_exposure: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TradingState {
+    Active,
+    /// New positions are rejected; existing positions can still be closed
+    Frozen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KillSwitchAction {
+    Freeze,
+    /// Close every open position at its current mark and freeze the portfolio
+    Flatten,
+    /// Unfreeze the portfolio
+    Resume,
+}
+
+pub type AlertId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AlertState {
+    /// The rule is breached and nobody has acknowledged it yet
+    Raised,
+    Acknowledged,
+    /// The rule is no longer breached
+    Cleared,
+}
+
+/// Raised when an alert rule is breached for a portfolio, and re-published whenever its state
+/// changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    pub id: AlertId,
+    pub rule: String,
+    pub portfolio_code: String,
+    pub severity: Severity,
+    pub message: String,
+    pub state: AlertState,
+    /// Seconds since the unix epoch at which the alert was raised
+    pub raised_at: u64,
+}
+
+/// A trade that a portfolio refused to book
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradeRejection {
+    pub portfolio_code: String,
+    pub reason: RejectReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RejectReason {
+    Frozen,
+    RestrictedSecurity {
+        ticker: String,
+    },
+    PositionSize {
+        ticker: String,
+        limit: u32,
+        resulting: u64,
+    },
+    OpenPositions {
+        limit: u32,
+    },
+    GrossExposure {
+        limit: f64,
+        resulting: f64,
+    },
+    NetExposure {
+        limit: f64,
+        resulting: f64,
+    },
+    UnknownPosition(PositionId),
+    InsufficientPosition {
+        security_id: SecurityId,
+        held: u32,
+        requested: u32,
+    },
+    InsufficientCash {
+        currency: String,
+        required: f64,
+        available: f64,
+    },
+    /// The security has not ticked, so there is no price to trade it at
+    NoPrice(SecurityId),
+    /// NAV after the trade would not cover the initial margin on every position
+    InitialMargin {
+        required: f64,
+        nav: f64,
+    },
+}
+
+impl RejectReason {
+    /// Whether the trade was rejected for breaching one of the portfolio's limits
+    pub fn is_limit_breach(&self) -> bool {
+        matches!(
+            self,
+            RejectReason::RestrictedSecurity { .. }
+                | RejectReason::PositionSize { .. }
+                | RejectReason::OpenPositions { .. }
+                | RejectReason::GrossExposure { .. }
+                | RejectReason::NetExposure { .. }
+        )
+    }
+}
+
+impl fmt::Display for TradeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rejected trade: ", self.portfolio_code)?;
+        match &self.reason {
+            RejectReason::RestrictedSecurity { ticker } => {
+                write!(f, "{} is restricted", ticker)
+            }
+            RejectReason::PositionSize {
+                ticker,
+                limit,
+                resulting,
+            } => write!(
+                f,
+                "{} position size {} would exceed limit {}",
+                ticker, resulting, limit
+            ),
+            RejectReason::OpenPositions { limit } => {
+                write!(f, "already at the limit of {} open positions", limit)
+            }
+            RejectReason::Frozen => write!(f, "portfolio is frozen"),
+            RejectReason::GrossExposure { limit, resulting } => write!(
+                f,
+                "gross exposure {} would exceed limit {}",
+                resulting, limit
+            ),
+            RejectReason::NetExposure { limit, resulting } => {
+                write!(f, "net exposure {} would exceed limit {}", resulting, limit)
+            }
+            RejectReason::UnknownPosition(id) => write!(f, "position {} does not exist", id),
+            RejectReason::InsufficientPosition {
+                security_id,
+                held,
+                requested,
+            } => write!(
+                f,
+                "cannot sell {} of security {} with only {} held",
+                requested, security_id, held
+            ),
+            RejectReason::InsufficientCash {
+                currency,
+                required,
+                available,
+            } => write!(
+                f,
+                "needs {} {} but only {} is available",
+                required, currency, available
+            ),
+            RejectReason::NoPrice(id) => write!(f, "security {} has no price", id),
+            RejectReason::InitialMargin { required, nav } => write!(
+                f,
+                "initial margin of {} would exceed nav of {}",
+                required, nav
+            ),
+        }
+    }
+}
+
+/// Breakdown of a portfolio's PnL. `new_trades`, `existing_positions` and `price_move` explain
+/// the change in total PnL since the previous stats cycle:
+/// - `new_trades`: positions opened this cycle, from cost to their current mark (or close)
+/// - `existing_positions`: positions carried into this cycle and closed during it, from their
+///   previous mark to their close
+/// - `price_move`: positions carried into this cycle and still open, from their previous mark to
+///   their current mark
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct PnlAttribution {
+    pub by_security: Vec<SecurityPnl>,
+    pub by_group: Vec<GroupPnl>,
+    pub new_trades: f64,
+    pub existing_positions: f64,
+    pub price_move: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SecurityPnl {
+    pub security_id: SecurityId,
+    pub ticker: String,
+    pub pnl: f64,
+    pub unrealized_pnl: f64,
+}
+
+/// PnL of every security tagged with `group` = `name`, e.g. `sector` = `Technology`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupPnl {
+    pub group: String,
+    pub name: String,
+    pub pnl: f64,
+    pub unrealized_pnl: f64,
+}
+
+/// Historical-simulation risk for a portfolio's current holdings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioRisk {
+    pub code: String,
+    /// Number of historical scenarios the metrics were computed from
+    pub observations: u32,
+    pub metrics: Vec<RiskMetric>,
+    /// Tickers held that have no returns yet, so are left out of the metrics
+    #[serde(default)]
+    pub excluded: Vec<String>,
+}
+
+/// A portfolio's performance against its benchmark. The portfolio's return over each stats cycle
+/// is its change in PnL over its gross exposure at the start of the cycle. `tracking_error` and
+/// `beta` are per stats cycle, over the same rolling window as risk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioBenchmark {
+    pub code: String,
+    pub benchmark: String,
+    pub observations: u32,
+    /// Compounded since the engine started
+    pub portfolio_return: f64,
+    /// Compounded since the engine started
+    pub benchmark_return: f64,
+    pub active_return: f64,
+    pub tracking_error: f64,
+    pub beta: f64,
+}
+
+/// Value-at-Risk and Expected Shortfall at a confidence level, both expressed as positive losses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RiskMetric {
+    pub confidence: f64,
+    pub var: f64,
+    pub expected_shortfall: f64,
+}
+
+/// A named set of price shocks. Shocks are applied in order, so a later shock compounds on top
+/// of any earlier shock that matched the same security
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    pub shocks: Vec<Shock>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Shock {
+    pub target: ShockTarget,
+    pub shift: PriceShift,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ShockTarget {
+    Security(String),
+    Group { group: String, name: String },
+    Universe,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum PriceShift {
+    /// Move the price by a fixed amount
+    Absolute(f64),
+    /// Move the price by a fraction of itself, e.g. `-0.1` for a 10% drop
+    Relative(f64),
+}
+
+/// A portfolio's PnL under a scenario, relative to its current marks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioResult {
+    pub scenario: String,
+    pub portfolio_code: String,
+    pub positions: Vec<PositionScenarioPnl>,
+    pub pnl: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionScenarioPnl {
+    pub position_id: PositionId,
+    pub ticker: String,
+    pub price: f64,
+    pub shocked_price: f64,
+    pub pnl: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BookLevel {
+    Firm,
+    Desk,
+    Strategy,
+    Portfolio,
+}
+
+/// Rolled-up stats for a node in the book hierarchy (firm, desk, strategy)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookStats {
+    pub code: String,
+    pub level: BookLevel,
+    pub children: Vec<String>,
+    pub position_count: u32,
+    pub trade_count: u32,
+    pub pnl: f64,
+    pub unrealized_pnl: f64,
+    pub gross_exposure: f64,
+    pub net_exposure: f64,
+    pub nav: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Security {
     pub id: SecurityId,
     pub ticker: String,
+    /// User-defined groupings such as sector or country, keyed by group name
+    #[serde(default)]
+    pub groups: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Security {
+    pub fn new(id: SecurityId, ticker: impl Into<String>) -> Self {
+        Self {
+            id,
+            ticker: ticker.into(),
+            groups: BTreeMap::default(),
+        }
+    }
+
+    pub fn with_group(mut self, group: impl Into<String>, name: impl Into<String>) -> Self {
+        self.groups.insert(group.into(), name.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Position {
     pub id: PositionId,
     pub security: Security,
@@ -26,3 +508,9 @@ pub struct Position {
     pub size: u32,
     pub unrealized_pnl: f64,
 }
+
+impl Position {
+    pub fn market_value(&self) -> f64 {
+        self.cost_basis + self.unrealized_pnl
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
     io::stdout,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::mpsc::{self, Sender},
     thread,
+    time::Duration,
 };
 
 use crossterm::{
@@ -10,29 +11,46 @@ use crossterm::{
     ExecutableCommand,
 };
 use piston_ipc::{
-    messages::{IpcMessage, Ping, Pong},
-    IpcReader,
+    commands::IpcCommandClient,
+    connection::{Connection, ConnectionEvent},
+    delta::PortfolioReplica,
+    messages::{
+        AcknowledgeAlert, GetIpcMetrics, IpcError, IpcMessage, ListSecurities, Ping, Pong, Resync,
+        RunScenarios, Subscription, Topic,
+    },
+    transport::Endpoint,
 };
+use piston_shared::{AlertId, AlertState};
 use ratatui::{
     backend::CrosstermBackend,
-    widgets::{Block, Borders, Paragraph, Row, Table},
+    widgets::{Block, Borders, Paragraph},
     Frame, Terminal,
 };
 
+/// Where to attach to the engine's message stream, e.g. `tcp://10.0.0.5:7400` for a remote engine
+const ENDPOINT_VAR: &str = "PISTON_IPC_ENDPOINT";
+
 fn main() -> std::io::Result<()> {
     enable_raw_mode()?;
     stdout().execute(EnterAlternateScreen)?;
     let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
 
     let (tx, rx) = mpsc::channel::<IpcMessage>();
-    thread::spawn(move || {
-        socket_thread(tx).expect("Failed to start socket thread");
-    });
+    let socket_tx = tx.clone();
+    thread::spawn(move || socket_thread(socket_tx));
 
+    let mut last_alert = None;
     let mut should_quit = false;
     while !should_quit {
-        terminal.draw(|f| ui(f, &rx))?;
-        should_quit = handle_events()?;
+        let msg = rx.try_recv().ok();
+        if let Some(IpcMessage::Alert(alert)) = &msg {
+            if alert.state == AlertState::Raised {
+                last_alert = Some(alert.id);
+            }
+        }
+
+        terminal.draw(|f| ui(f, msg))?;
+        should_quit = handle_events(&tx, &mut last_alert)?;
     }
 
     disable_raw_mode()?;
@@ -40,58 +58,413 @@ fn main() -> std::io::Result<()> {
     Ok(())
 }
 
-fn handle_events() -> std::io::Result<bool> {
-    if event::poll(std::time::Duration::from_millis(50))? {
+fn handle_events(
+    tx: &Sender<IpcMessage>,
+    last_alert: &mut Option<AlertId>,
+) -> std::io::Result<bool> {
+    if event::poll(Duration::from_millis(50))? {
         if let Event::Key(key) = event::read()? {
-            if key.kind == event::KeyEventKind::Press && key.code == KeyCode::Char('q') {
-                return Ok(true);
+            if key.kind == event::KeyEventKind::Press {
+                let request = match key.code {
+                    KeyCode::Char('q') => return Ok(true),
+                    // Run the engine's configured scenarios against every portfolio
+                    KeyCode::Char('s') => Some(IpcMessage::RunScenarios(RunScenarios::default())),
+                    // List the security universe with the latest prices
+                    KeyCode::Char('l') => Some(IpcMessage::ListSecurities(ListSecurities)),
+                    // Show how the message stream is keeping up with its subscribers
+                    KeyCode::Char('m') => Some(IpcMessage::GetIpcMetrics(GetIpcMetrics)),
+                    // Acknowledge the most recently raised alert
+                    KeyCode::Char('a') => last_alert
+                        .take()
+                        .map(|id| IpcMessage::AcknowledgeAlert(AcknowledgeAlert { id })),
+                    _ => None,
+                };
+
+                if let Some(request) = request {
+                    let tx = tx.clone();
+                    thread::spawn(move || request_thread(tx, request));
+                }
             }
         }
     }
     Ok(false)
 }
 
-fn socket_thread(tx: Sender<IpcMessage>) -> std::io::Result<()> {
-    let reader = IpcReader::new()?;
-    let stream = reader.as_stream()?;
+/// The engine endpoint named by `PISTON_IPC_ENDPOINT`, or the local socket
+fn endpoint() -> Result<Endpoint, String> {
+    match std::env::var(ENDPOINT_VAR) {
+        Ok(endpoint) => endpoint.parse(),
+        Err(_) => Ok(Endpoint::default()),
+    }
+}
 
-    for msg in stream {
-        match msg {
-            Ok(msg) => {
-                tx.send(msg).expect("Failed to send message");
+fn request_thread(tx: Sender<IpcMessage>, request: IpcMessage) {
+    let response = endpoint()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+        .and_then(|endpoint| IpcCommandClient::connect(&endpoint, "piston_tui"))
+        .and_then(|mut client| client.request(request))
+        .unwrap_or_else(|e| {
+            IpcMessage::Error(IpcError {
+                message: format!("Request failed, {}", e),
+            })
+        });
+
+    tx.send(response).expect("Failed to send message");
+}
+
+fn socket_thread(tx: Sender<IpcMessage>) {
+    // Portfolio codes given on the command line narrow the stream down to those portfolios
+    let portfolios: Vec<_> = std::env::args().skip(1).map(Topic::Portfolio).collect();
+    let mut setup = vec![];
+    if !portfolios.is_empty() {
+        setup.push(IpcMessage::Unsubscribe(Subscription {
+            topics: vec![Topic::Portfolios],
+        }));
+        setup.push(IpcMessage::Subscribe(Subscription { topics: portfolios }));
+    }
+
+    // Portfolios arrive as snapshots followed by deltas, and are shown as the stats they add up to
+    let endpoint = match endpoint() {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            let _ = tx.send(IpcMessage::Error(IpcError { message: e }));
+            return;
+        }
+    };
+    let mut connection = Connection::new(endpoint, "piston_tui", setup);
+    let mut replica = PortfolioReplica::default();
+    while let Some(event) = connection.next() {
+        let msg = match event {
+            ConnectionEvent::Connected => {
+                replica = PortfolioReplica::default();
+                continue;
             }
-            Err(e) => {
-                println!("Failed to read message, {:?}", e);
+            ConnectionEvent::Disconnected(reason) => IpcMessage::Error(IpcError {
+                message: format!("Disconnected from the engine, {}. Reconnecting", reason),
+            }),
+            ConnectionEvent::Message(msg) => *msg,
+        };
+
+        match msg {
+            IpcMessage::PortfolioSnapshots(snapshots) => {
+                for snapshot in snapshots.snapshots {
+                    let stats = replica.snapshot(snapshot).clone();
+                    tx.send(IpcMessage::PortfolioStats(stats))
+                        .expect("Failed to send message");
+                }
             }
+            IpcMessage::PortfolioDelta(delta) => match replica.apply(delta) {
+                Ok(Some(stats)) => tx
+                    .send(IpcMessage::PortfolioStats(stats.clone()))
+                    .expect("Failed to send message"),
+                Ok(None) => {}
+                // A failed send means the connection dropped, and it resyncs when it is back
+                Err(code) => {
+                    let _ = connection.send(&IpcMessage::Resync(Resync {
+                        portfolio_codes: vec![code],
+                    }));
+                }
+            },
+            msg => tx.send(msg).expect("Failed to send message"),
         }
     }
+}
 
-    Ok(())
+fn ui(frame: &mut Frame, msg: Option<IpcMessage>) {
+    let Some(msg) = msg else {
+        return;
+    };
+
+    frame.render_widget(
+        Paragraph::new(describe(msg)).block(Block::default().title("Piston").borders(Borders::ALL)),
+        frame.size(),
+    );
 }
 
-fn ui(frame: &mut Frame, rx: &Receiver<IpcMessage>) {
-    match rx.try_recv().ok() {
-        Some(msg) => {
-            let text = match msg {
-                IpcMessage::PortfolioStats(stats) => {
+fn describe(msg: IpcMessage) -> String {
+    match msg {
+        IpcMessage::PortfolioStats(stats) => {
+            format!(
+                "Portfolio: {} ({:?})\nPositions: {}\nTrades: {}\nRejected Trades: {}\nRealized PnL: {}\nUnrealized PnL: {}\nNAV: {}\n{}\nMargin: {:?}, initial {} (excess {}), maintenance {} (excess {}), leverage {}\nExposure: gross {}, net {}, long {}, short {}{}\nPnL Explain: new trades {}, existing positions {}, price move {}",
+                stats.code,
+                stats.trading_state,
+                stats.positions.len(),
+                stats.trade_count,
+                stats.rejected_trade_count,
+                stats.pnl,
+                stats.unrealized_pnl,
+                stats.nav,
+                stats
+                    .cash
+                    .iter()
+                    .map(|c| format!(
+                        "{}: cash {}, market value {}, fees {}, dividends {}",
+                        c.currency, c.cash, c.market_value, c.fees, c.dividends
+                    ))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                stats.margin.state,
+                stats.margin.initial_requirement,
+                stats.margin.initial_excess,
+                stats.margin.maintenance_requirement,
+                stats.margin.maintenance_excess,
+                stats.margin.leverage,
+                stats.exposure.gross,
+                stats.exposure.net,
+                stats.exposure.long,
+                stats.exposure.short,
+                stats
+                    .exposure
+                    .largest
+                    .as_ref()
+                    .map(|l| format!(
+                        ", largest {} {} ({:.1}%)",
+                        l.ticker,
+                        l.market_value,
+                        l.concentration * 100f64
+                    ))
+                    .unwrap_or_default(),
+                stats.attribution.new_trades,
+                stats.attribution.existing_positions,
+                stats.attribution.price_move
+            )
+        }
+        IpcMessage::BookStats(stats) => {
+            format!(
+                "{:?}: {}\nChildren: {}\nPositions: {}\nTrades: {}\nRealized PnL: {}\nUnrealized PnL: {}\nGross Exposure: {}\nNet Exposure: {}\nNAV: {}",
+                stats.level,
+                stats.code,
+                stats.children.join(", "),
+                stats.position_count,
+                stats.trade_count,
+                stats.pnl,
+                stats.unrealized_pnl,
+                stats.gross_exposure,
+                stats.net_exposure,
+                stats.nav
+            )
+        }
+        IpcMessage::PortfolioRisk(risk) => {
+            let metrics: Vec<_> = risk
+                .metrics
+                .iter()
+                .map(|m| {
                     format!(
-                        "Portfolio: {}\nPositions: {}\nTrades: {}\nRealized PnL: {}\nUnrealized PnL: {}",
-                        stats.code,
-                        stats.positions.len(),
-                        stats.trade_count,
-                        stats.pnl,
-                        stats.unrealized_pnl
+                        "{}%: VaR {}, ES {}",
+                        m.confidence * 100f64,
+                        m.var,
+                        m.expected_shortfall
                     )
-                }
-                IpcMessage::Ping(Ping) => "Ping".to_string(),
-                IpcMessage::Pong(Pong) => "Pong".to_string(),
+                })
+                .collect();
+            let excluded = match risk.excluded.is_empty() {
+                true => String::new(),
+                false => format!("\nExcluded: {}", risk.excluded.join(", ")),
             };
-
-            frame.render_widget(
-                Paragraph::new(text).block(Block::default().title("Piston").borders(Borders::ALL)),
-                frame.size(),
-            );
+            format!(
+                "Portfolio Risk: {}\nObservations: {}\n{}{}",
+                risk.code,
+                risk.observations,
+                metrics.join("\n"),
+                excluded
+            )
         }
-        _ => {}
-    };
+        IpcMessage::PortfolioBenchmark(b) => format!(
+            "Benchmark: {} vs {}\nObservations: {}\nPortfolio Return: {}\nBenchmark Return: {}\nActive Return: {}\nTracking Error: {}\nBeta: {}",
+            b.code,
+            b.benchmark,
+            b.observations,
+            b.portfolio_return,
+            b.benchmark_return,
+            b.active_return,
+            b.tracking_error,
+            b.beta
+        ),
+        IpcMessage::ScenarioResults(results) => {
+            let lines: Vec<_> = results
+                .results
+                .iter()
+                .map(|r| format!("{} / {}: {}", r.scenario, r.portfolio_code, r.pnl))
+                .collect();
+            format!("Scenario PnL\n{}", lines.join("\n"))
+        }
+        IpcMessage::Alert(alert) => format!(
+            "Alert {} ({:?}, {:?})\nRule: {}\nPortfolio: {}\n{}",
+            alert.id, alert.severity, alert.state, alert.rule, alert.portfolio_code, alert.message
+        ),
+        IpcMessage::PortfolioOrders(orders) => format!(
+            "Orders: {}\nWorking: {}\nBuy Exposure: {}\nSell Exposure: {}",
+            orders.code,
+            orders.orders.len(),
+            orders.buy_exposure,
+            orders.sell_exposure
+        ),
+        IpcMessage::OrderUpdate(order) => format!(
+            "Order {} ({:?})\nPortfolio: {}\n{:?} {} {} ({:?})\nFilled: {} at {}{}",
+            order.id,
+            order.status,
+            order.portfolio_code,
+            order.side,
+            order.quantity,
+            order.security.ticker,
+            order.order_type,
+            order.filled_quantity,
+            order.average_fill_price,
+            order
+                .reject_reason
+                .map(|r| format!("\nReason: {}", r))
+                .unwrap_or_default()
+        ),
+        IpcMessage::SubmitOrder(order) => format!(
+            "Submit {:?} {} of security {} for {}",
+            order.side, order.quantity, order.security_id, order.portfolio_code
+        ),
+        IpcMessage::CancelOrder(cancel) => format!("Cancel Order {}", cancel.id),
+        IpcMessage::ReportFill(fill) => format!(
+            "Fill {} at {} on order {}",
+            fill.quantity, fill.price, fill.order_id
+        ),
+        IpcMessage::PayDividend(d) => format!(
+            "Pay Dividend of {} per share on security {}",
+            d.per_share, d.security_id
+        ),
+        IpcMessage::DividendPaid(d) => format!(
+            "Dividend of {} per share on security {} paid, {} in total",
+            d.per_share, d.security_id, d.total
+        ),
+        IpcMessage::Tick(tick) => format!("Tick: {} {}", tick.ticker, tick.price),
+        IpcMessage::Trade(trade) => format!(
+            "Trade: {}\n{:?} {} {} at {}\nRealized PnL: {}",
+            trade.portfolio_code,
+            trade.side,
+            trade.size,
+            trade.ticker,
+            trade.price,
+            trade.realized_pnl
+        ),
+        IpcMessage::Subscribe(s) => format!("Subscribe to {:?}", s.topics),
+        IpcMessage::Unsubscribe(s) => format!("Unsubscribe from {:?}", s.topics),
+        IpcMessage::Subscriptions(s) => format!("Subscribed to {:?}", s.topics),
+        IpcMessage::AcknowledgeAlert(ack) => format!("Acknowledge Alert {}", ack.id),
+        IpcMessage::KillSwitch(kill) => format!("{:?} {}", kill.action, kill.portfolio_code),
+        IpcMessage::TradingStatus(status) => {
+            format!(
+                "Portfolio: {}\nState: {:?}",
+                status.portfolio_code, status.state
+            )
+        }
+        IpcMessage::RunScenarios(_) => "Run Scenarios".to_string(),
+        IpcMessage::Error(e) => format!("Error: {}", e.message),
+        IpcMessage::Ping(Ping) => "Ping".to_string(),
+        IpcMessage::Pong(Pong) => "Pong".to_string(),
+        IpcMessage::PortfolioDelta(d) => format!(
+            "Portfolio Delta: {} #{}\n{} changes",
+            d.code,
+            d.sequence,
+            d.changes.len()
+        ),
+        IpcMessage::Resync(r) => format!("Resync {:?}", r.portfolio_codes),
+        IpcMessage::PortfolioSnapshots(s) => {
+            let lines: Vec<_> = s
+                .snapshots
+                .iter()
+                .map(|s| format!("{} #{}", s.stats.code, s.sequence))
+                .collect();
+            format!("Portfolio Snapshots\n{}", lines.join("\n"))
+        }
+        IpcMessage::GetIpcMetrics(_) => "Get IPC Metrics".to_string(),
+        IpcMessage::IpcMetrics(m) => {
+            let lines: Vec<_> = m
+                .subscribers
+                .iter()
+                .map(|s| {
+                    format!(
+                        "{} ({:?}): {} queued, {} sent, {} dropped, {} conflated",
+                        s.peer, s.codec, s.queued, s.sent, s.dropped, s.conflated
+                    )
+                })
+                .collect();
+            format!(
+                "IPC Metrics\nDropped: {}\nConflated: {}\nSlow Consumers Disconnected: {}\n{}",
+                m.dropped,
+                m.conflated,
+                m.slow_consumers_disconnected,
+                lines.join("\n")
+            )
+        }
+        IpcMessage::Request(r) => format!("Request {}\n{}", r.id, describe(*r.request)),
+        IpcMessage::Response(r) => format!("Response {}\n{}", r.id, describe(*r.response)),
+        IpcMessage::ListPortfolios(_) => "List Portfolios".to_string(),
+        IpcMessage::Portfolios(p) => {
+            let lines: Vec<_> = p
+                .portfolios
+                .iter()
+                .map(|p| {
+                    format!(
+                        "{}: {} positions, PnL {}, NAV {}",
+                        p.code, p.position_count, p.summary.pnl, p.summary.nav
+                    )
+                })
+                .collect();
+            format!("Portfolios\n{}", lines.join("\n"))
+        }
+        IpcMessage::GetPortfolio(p) => format!("Get Portfolio {}", p.code),
+        IpcMessage::GetPnlHistory(p) => format!("Get PnL History {}", p.code),
+        IpcMessage::PnlHistory(h) => match h.points.last() {
+            Some(last) => format!(
+                "PnL History {}\n{} points\nLatest PnL: {}\nLatest NAV: {}",
+                h.code,
+                h.points.len(),
+                last.pnl,
+                last.nav
+            ),
+            None => format!("PnL History {}\nNo points yet", h.code),
+        },
+        IpcMessage::ListSecurities(ListSecurities) => "List Securities".to_string(),
+        IpcMessage::Securities(s) => {
+            let lines: Vec<_> = s
+                .securities
+                .iter()
+                .map(|s| match s.price {
+                    Some(price) => format!("{} ({}): {}", s.security.ticker, s.security.id, price),
+                    None => format!("{} ({}): no price", s.security.ticker, s.security.id),
+                })
+                .collect();
+            format!("Securities\n{}", lines.join("\n"))
+        }
+        IpcMessage::GetSecurity(s) => format!("Get Security {}", s.security_id),
+        IpcMessage::Security(s) => match s.price {
+            Some(price) => format!("{} ({}): {}", s.security.ticker, s.security.id, price),
+            None => format!("{} ({}): no price", s.security.ticker, s.security.id),
+        },
+        IpcMessage::GetPosition(p) => {
+            format!("Get Position {} in {}", p.position_id, p.portfolio_code)
+        }
+        IpcMessage::PositionDetail(p) => format!(
+            "Position {} in {}\n{} {}\nCost Basis: {}\nMarket Value: {}\nUnrealized PnL: {}",
+            p.position.id,
+            p.portfolio_code,
+            p.position.size,
+            p.position.security.ticker,
+            p.position.cost_basis,
+            p.position.market_value(),
+            p.position.unrealized_pnl
+        ),
+        IpcMessage::InjectTrade(t) => format!(
+            "Inject {:?} {} of security {} at {} into {}",
+            t.side, t.size, t.security_id, t.price, t.portfolio_code
+        ),
+        IpcMessage::TradeInjected(t) => format!(
+            "Trade Injected: {}\n{:?} {} of security {} at {}{}",
+            t.portfolio_code,
+            t.side,
+            t.size,
+            t.security_id,
+            t.price,
+            t.position_id
+                .map(|id| format!("\nOpened Position {}", id))
+                .unwrap_or_default()
+        ),
+        IpcMessage::InjectTick(t) => format!("Inject Tick: {} {}", t.security_id, t.price),
+    }
 }